@@ -0,0 +1,203 @@
+use tokio::sync::Notify;
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::Poll;
+
+/// What to do with a new line when an event handler's queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+  /// Discard the oldest queued line to make room for the new one.
+  DropOldest,
+  /// Discard the new line.
+  DropNewest,
+  /// Stop reading the server's output until there is room.
+  /// Beware that this lets a slow event handler stall the server.
+  Block
+}
+
+/// Options for how lines are queued up for an event handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DispatchOptions {
+  /// The maximum number of lines waiting to be handled.
+  pub capacity: usize,
+  /// What to do when the queue is full.
+  pub overflow: Overflow
+}
+
+impl Default for DispatchOptions {
+  fn default() -> Self {
+    DispatchOptions {
+      capacity: 1024,
+      overflow: Overflow::DropOldest
+    }
+  }
+}
+
+/// A bounded queue sitting between the server's output and a single event handler.
+/// Only items pushed with `push` count towards the capacity and may be dropped,
+/// items pushed with `push_reliable` are always delivered.
+pub(crate) struct HandlerQueue<'a, T> {
+  options: DispatchOptions,
+  state: Mutex<QueueState<T>>,
+  pushed: Notify,
+  popped: Notify,
+  dropped: &'a AtomicU64
+}
+
+struct QueueState<T> {
+  /// Each item along with whether it may be dropped.
+  items: VecDeque<(T, bool)>,
+  /// The number of items that may be dropped.
+  droppable: usize,
+  closed: bool
+}
+
+impl<'a, T> HandlerQueue<'a, T> {
+  pub(crate) fn new(options: DispatchOptions, dropped: &'a AtomicU64) -> Self {
+    HandlerQueue {
+      options,
+      state: Mutex::new(QueueState { items: VecDeque::new(), droppable: 0, closed: false }),
+      pushed: Notify::new(),
+      popped: Notify::new(),
+      dropped
+    }
+  }
+
+  /// Queues an item, applying the overflow policy if the queue is full.
+  pub(crate) async fn push(&self, item: T) {
+    let capacity = self.options.capacity.max(1);
+    loop {
+      {
+        let mut state = self.state.lock().unwrap();
+        if state.droppable < capacity {
+          state.items.push_back((item, true));
+          state.droppable += 1;
+          break;
+        };
+
+        match self.options.overflow {
+          Overflow::DropOldest => {
+            let oldest = state.items.iter().position(|&(_, droppable)| droppable)
+              .expect("queue is full of droppable items");
+            state.items.remove(oldest);
+            state.items.push_back((item, true));
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            break;
+          },
+          Overflow::DropNewest => {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+          },
+          Overflow::Block => ()
+        };
+      };
+
+      self.popped.notified().await;
+    };
+
+    self.pushed.notify_one();
+  }
+
+  /// Queues an item that must not be dropped, ignoring the capacity and overflow policy.
+  pub(crate) fn push_reliable(&self, item: T) {
+    self.state.lock().unwrap().items.push_back((item, false));
+    self.pushed.notify_one();
+  }
+
+  /// Takes the next item, or returns `None` once the queue is closed and empty.
+  pub(crate) async fn pop(&self) -> Option<T> {
+    loop {
+      {
+        let mut state = self.state.lock().unwrap();
+        if let Some((item, droppable)) = state.items.pop_front() {
+          if droppable { state.droppable -= 1 };
+          drop(state);
+          self.popped.notify_one();
+          return Some(item);
+        } else if state.closed {
+          return None;
+        };
+      };
+
+      self.pushed.notified().await;
+    };
+  }
+
+  /// Marks that no more items will be pushed.
+  pub(crate) fn close(&self) {
+    self.state.lock().unwrap().closed = true;
+    self.pushed.notify_one();
+  }
+}
+
+/// Drives an event handler's future to completion, logging and discarding any panic that occurs
+/// while polling it so that one misbehaving handler cannot bring down the whole puppet.
+pub(crate) async fn isolate<F>(mut future: Pin<Box<F>>)
+where F: Future<Output = ()> + ?Sized {
+  let result = std::future::poll_fn(move |cx| {
+    match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+      Ok(Poll::Ready(())) => Poll::Ready(Ok(())),
+      Ok(Poll::Pending) => Poll::Pending,
+      Err(payload) => Poll::Ready(Err(payload))
+    }
+  }).await;
+
+  if let Err(payload) = result {
+    eprintln!("[Puppet] Event handler panicked: {}", panic_message(&*payload));
+  };
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+  if let Some(message) = payload.downcast_ref::<&str>() {
+    message
+  } else if let Some(message) = payload.downcast_ref::<String>() {
+    message
+  } else {
+    "unknown panic"
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  async fn drain(queue: &HandlerQueue<'_, u32>) -> Vec<u32> {
+    queue.close();
+    let mut items = Vec::new();
+    while let Some(item) = queue.pop().await {
+      items.push(item);
+    };
+    items
+  }
+
+  #[tokio::test]
+  async fn drop_oldest_keeps_reliable_items() {
+    let dropped = AtomicU64::new(0);
+    let queue = HandlerQueue::new(DispatchOptions { capacity: 2, overflow: Overflow::DropOldest }, &dropped);
+    queue.push_reliable(0);
+    queue.push(1).await;
+    queue.push(2).await;
+    queue.push(3).await;
+    queue.push_reliable(4);
+    assert_eq!(drain(&queue).await, [0, 2, 3, 4]);
+    assert_eq!(dropped.load(Ordering::Relaxed), 1);
+  }
+
+  #[tokio::test]
+  async fn reliable_items_ignore_capacity() {
+    let dropped = AtomicU64::new(0);
+    let queue = HandlerQueue::new(DispatchOptions { capacity: 1, overflow: Overflow::DropNewest }, &dropped);
+    queue.push(1).await;
+    queue.push_reliable(2);
+    queue.push_reliable(3);
+    queue.push(4).await;
+    assert_eq!(drain(&queue).await, [1, 2, 3]);
+    assert_eq!(dropped.load(Ordering::Relaxed), 1);
+  }
+}
@@ -0,0 +1,332 @@
+use async_trait::async_trait;
+
+use std::io;
+#[cfg(feature = "parsing")]
+use std::marker::PhantomData;
+use std::process::ExitStatus;
+use std::sync::Arc;
+
+use crate::dispatch::isolate;
+#[cfg(feature = "parsing")]
+use crate::parsing::{ConsoleLine, ConsoleLineKind};
+use crate::puppet::{EventHandler, Line, Puppet};
+
+/// Implements `EventHandler` for a collection by dispatching every event to each handler
+/// yielded by `$handlers`, in order. Each handler is isolated from the others' panics.
+macro_rules! event_handler_each {
+  (impl[$($generics:tt)*] for $ty:ty, |$this:ident| $handlers:expr) => {
+    #[async_trait]
+    impl<$($generics)*> EventHandler for $ty {
+      async fn process_spawned(&self, puppet: &Puppet) {
+        let $this = self;
+        for handler in $handlers { isolate(handler.process_spawned(puppet)).await };
+      }
+
+      async fn dispatch_line(&self, puppet: &Puppet, line: &Line) {
+        let $this = self;
+        for handler in $handlers { isolate(handler.dispatch_line(puppet, line)).await };
+      }
+
+      #[cfg(feature = "parsing")]
+      async fn dispatch_parsed_line(&self, puppet: &Puppet, line: &Line) {
+        let $this = self;
+        for handler in $handlers { isolate(handler.dispatch_parsed_line(puppet, line)).await };
+      }
+
+      async fn console_line(&self, puppet: &Puppet, line: &str) {
+        let $this = self;
+        for handler in $handlers { isolate(handler.console_line(puppet, line)).await };
+      }
+
+      async fn stderr_line(&self, puppet: &Puppet, line: &str) {
+        let $this = self;
+        for handler in $handlers { isolate(handler.stderr_line(puppet, line)).await };
+      }
+
+      #[cfg(feature = "parsing")]
+      async fn console_event(&self, puppet: &Puppet, line: &ConsoleLine) {
+        let $this = self;
+        for handler in $handlers { isolate(handler.console_event(puppet, line)).await };
+      }
+
+      #[cfg(feature = "parsing")]
+      async fn server_ready(&self, puppet: &Puppet, time: f64) {
+        let $this = self;
+        for handler in $handlers { isolate(handler.server_ready(puppet, time)).await };
+      }
+
+      #[cfg(feature = "parsing")]
+      async fn server_stopping(&self, puppet: &Puppet) {
+        let $this = self;
+        for handler in $handlers { isolate(handler.server_stopping(puppet)).await };
+      }
+
+      async fn process_exited(&self, puppet: &Puppet, status: ExitStatus) {
+        let $this = self;
+        for handler in $handlers { isolate(handler.process_exited(puppet, status)).await };
+      }
+
+      async fn stdin_closed(&self, puppet: &Puppet) {
+        let $this = self;
+        for handler in $handlers { isolate(handler.stdin_closed(puppet)).await };
+      }
+
+      async fn wrapper_command(&self, puppet: &Puppet, command: &str) {
+        let $this = self;
+        for handler in $handlers { isolate(handler.wrapper_command(puppet, command)).await };
+      }
+
+      async fn io_error(&self, puppet: &Puppet, error: &io::Error) {
+        let $this = self;
+        for handler in $handlers { isolate(handler.io_error(puppet, error)).await };
+      }
+    }
+  };
+}
+
+macro_rules! event_handler_tuple {
+  ($($name:ident $index:tt),+) => {
+    event_handler_each!(impl[$($name: EventHandler),+] for ($($name,)+), |this| [$(&this.$index as &dyn EventHandler),+]);
+  };
+}
+
+event_handler_each!(impl[H: EventHandler + ?Sized] for Box<H>, |this| std::iter::once(&**this));
+event_handler_each!(impl[H: EventHandler + ?Sized] for Arc<H>, |this| std::iter::once(&**this));
+event_handler_each!(impl[H: EventHandler] for Option<H>, |this| this.iter());
+event_handler_each!(impl[H: EventHandler] for Vec<H>, |this| this.iter());
+
+event_handler_tuple!(A 0);
+event_handler_tuple!(A 0, B 1);
+event_handler_tuple!(A 0, B 1, C 2);
+event_handler_tuple!(A 0, B 1, C 2, D 3);
+event_handler_tuple!(A 0, B 1, C 2, D 3, E 4);
+event_handler_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
+event_handler_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+event_handler_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+/// Extension methods for wrapping an `EventHandler` in a combinator.
+pub trait EventHandlerExt: EventHandler + Sized {
+  /// Only pass console lines (from both stdout and stderr) for which `predicate` returns `true` to this handler.
+  /// Lines that are rejected are also hidden from the typed callbacks derived from them,
+  /// such as `console_event`. Lifecycle events and wrapper commands are always passed through.
+  fn filter<F>(self, predicate: F) -> Filter<Self, F>
+  where F: Fn(&str) -> bool + Send + Sync {
+    Filter { handler: self, predicate }
+  }
+
+  /// Only pass typed console events of kind `K` to this handler, such as `only::<ChatMessage>()`.
+  /// Raw console lines are not passed at all. Lifecycle events and wrapper commands are always passed through.
+  #[cfg(feature = "parsing")]
+  fn only<K: ConsoleLineKind>(self) -> Only<Self, K> {
+    Only { handler: self, kind: PhantomData }
+  }
+
+  /// Put this handler in a `Box`, for building up a `Vec<Box<dyn EventHandler>>`.
+  fn boxed(self) -> Box<dyn EventHandler>
+  where Self: 'static {
+    Box::new(self)
+  }
+}
+
+impl<H: EventHandler> EventHandlerExt for H {}
+
+/// An event handler that only receives console lines matching a predicate.
+/// Created by `EventHandlerExt::filter`.
+#[derive(Debug)]
+pub struct Filter<H, F> {
+  handler: H,
+  predicate: F
+}
+
+#[async_trait]
+impl<H, F> EventHandler for Filter<H, F>
+where H: EventHandler, F: Fn(&str) -> bool + Send + Sync {
+  async fn process_spawned(&self, puppet: &Puppet) {
+    self.handler.process_spawned(puppet).await;
+  }
+
+  async fn dispatch_line(&self, puppet: &Puppet, line: &Line) {
+    if (self.predicate)(&line.text) {
+      self.handler.dispatch_line(puppet, line).await;
+    };
+  }
+
+  #[cfg(feature = "parsing")]
+  async fn dispatch_parsed_line(&self, puppet: &Puppet, line: &Line) {
+    if (self.predicate)(&line.text) {
+      self.handler.dispatch_parsed_line(puppet, line).await;
+    };
+  }
+
+  async fn console_line(&self, puppet: &Puppet, line: &str) {
+    if (self.predicate)(line) {
+      self.handler.console_line(puppet, line).await;
+    };
+  }
+
+  async fn stderr_line(&self, puppet: &Puppet, line: &str) {
+    if (self.predicate)(line) {
+      self.handler.stderr_line(puppet, line).await;
+    };
+  }
+
+  // Typed callbacks can only be filtered when they are dispatched from a line, which the predicate needs
+  #[cfg(feature = "parsing")]
+  async fn console_event(&self, puppet: &Puppet, line: &ConsoleLine) {
+    self.handler.console_event(puppet, line).await;
+  }
+
+  #[cfg(feature = "parsing")]
+  async fn server_ready(&self, puppet: &Puppet, time: f64) {
+    self.handler.server_ready(puppet, time).await;
+  }
+
+  #[cfg(feature = "parsing")]
+  async fn server_stopping(&self, puppet: &Puppet) {
+    self.handler.server_stopping(puppet).await;
+  }
+
+  async fn process_exited(&self, puppet: &Puppet, status: ExitStatus) {
+    self.handler.process_exited(puppet, status).await;
+  }
+
+  async fn stdin_closed(&self, puppet: &Puppet) {
+    self.handler.stdin_closed(puppet).await;
+  }
+
+  async fn wrapper_command(&self, puppet: &Puppet, command: &str) {
+    self.handler.wrapper_command(puppet, command).await;
+  }
+
+  async fn io_error(&self, puppet: &Puppet, error: &io::Error) {
+    self.handler.io_error(puppet, error).await;
+  }
+}
+
+/// An event handler that only receives typed console events of one kind.
+/// Created by `EventHandlerExt::only`.
+#[cfg(feature = "parsing")]
+#[derive(Debug)]
+pub struct Only<H, K> {
+  handler: H,
+  kind: PhantomData<fn() -> K>
+}
+
+#[cfg(feature = "parsing")]
+#[async_trait]
+impl<H, K> EventHandler for Only<H, K>
+where H: EventHandler, K: ConsoleLineKind {
+  async fn process_spawned(&self, puppet: &Puppet) {
+    self.handler.process_spawned(puppet).await;
+  }
+
+  async fn dispatch_line(&self, puppet: &Puppet, line: &Line) {
+    self.dispatch_parsed_line(puppet, line).await;
+  }
+
+  async fn dispatch_parsed_line(&self, puppet: &Puppet, line: &Line) {
+    if line.parsed().is_some_and(K::matches) {
+      self.handler.dispatch_parsed_line(puppet, line).await;
+    };
+  }
+
+  async fn console_event(&self, puppet: &Puppet, line: &ConsoleLine) {
+    if K::matches(line) {
+      self.handler.console_event(puppet, line).await;
+    };
+  }
+
+  async fn server_ready(&self, puppet: &Puppet, time: f64) {
+    if K::matches(&ConsoleLine::DoneLoading { time }) {
+      self.handler.server_ready(puppet, time).await;
+    };
+  }
+
+  async fn server_stopping(&self, puppet: &Puppet) {
+    if K::matches(&ConsoleLine::StoppingServer) {
+      self.handler.server_stopping(puppet).await;
+    };
+  }
+
+  async fn process_exited(&self, puppet: &Puppet, status: ExitStatus) {
+    self.handler.process_exited(puppet, status).await;
+  }
+
+  async fn stdin_closed(&self, puppet: &Puppet) {
+    self.handler.stdin_closed(puppet).await;
+  }
+
+  async fn wrapper_command(&self, puppet: &Puppet, command: &str) {
+    self.handler.wrapper_command(puppet, command).await;
+  }
+
+  async fn io_error(&self, puppet: &Puppet, error: &io::Error) {
+    self.handler.io_error(puppet, error).await;
+  }
+}
+
+#[cfg(all(test, feature = "parsing", unix))]
+mod tests {
+  use std::process::Stdio;
+  use std::sync::Mutex;
+
+  use super::*;
+  use crate::parsing::kind;
+  use crate::puppet::OutputStream;
+
+  /// Records the callbacks it receives.
+  #[derive(Debug, Default)]
+  struct Recorder(Mutex<Vec<String>>);
+
+  #[async_trait]
+  impl EventHandler for Recorder {
+    async fn console_line(&self, _puppet: &Puppet, line: &str) {
+      self.0.lock().unwrap().push(format!("line {}", line));
+    }
+
+    async fn console_event(&self, _puppet: &Puppet, line: &ConsoleLine) {
+      self.0.lock().unwrap().push(format!("event {:?}", line));
+    }
+  }
+
+  const CHAT: &str = "[12:00:00] [Server thread/INFO]: <Notch> hello";
+  const SECRET_CHAT: &str = "[12:00:00] [Server thread/INFO]: <Notch> secret";
+  const JOINED: &str = "[12:00:00] [Server thread/INFO]: Notch joined the game";
+
+  /// Dispatches each of the lines to `handler`.
+  async fn dispatch(handler: impl EventHandler) {
+    let child = tokio::process::Command::new("cat")
+      .stdin(Stdio::piped()).stdout(Stdio::piped())
+      .kill_on_drop(true)
+      .spawn().unwrap();
+    let puppet = Puppet::from_child(child);
+    for text in [CHAT, SECRET_CHAT, JOINED] {
+      handler.dispatch_line(&puppet, &Line::new(text, OutputStream::Stdout)).await;
+    };
+  }
+
+  #[tokio::test]
+  async fn filter_hides_derived_events() {
+    let recorder = Arc::new(Recorder::default());
+    dispatch(recorder.clone().filter(|line| !line.contains("secret"))).await;
+    let received = recorder.0.lock().unwrap();
+    assert_eq!(received.len(), 4);
+    assert!(received.iter().all(|received| !received.contains("secret")));
+  }
+
+  #[tokio::test]
+  async fn filter_then_only() {
+    let recorder = Arc::new(Recorder::default());
+    dispatch(recorder.clone().filter(|line| !line.contains("secret")).only::<kind::ChatMessage>()).await;
+    let received = recorder.0.lock().unwrap();
+    assert_eq!(*received, [format!("event {:?}", ConsoleLine::parse_from(CHAT).unwrap())]);
+  }
+
+  #[tokio::test]
+  async fn only_then_filter() {
+    let recorder = Arc::new(Recorder::default());
+    dispatch(recorder.clone().only::<kind::ChatMessage>().filter(|line| !line.contains("secret"))).await;
+    let received = recorder.0.lock().unwrap();
+    assert_eq!(*received, [format!("event {:?}", ConsoleLine::parse_from(CHAT).unwrap())]);
+  }
+}
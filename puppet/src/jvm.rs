@@ -0,0 +1,102 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Heaps larger than this get the large-heap variant of Aikar's flags.
+const AIKAR_LARGE_HEAP: u64 = 12 * 1024 * 1024 * 1024;
+
+/// The directory (relative to the server's working directory) that GC logs are written to.
+pub(crate) const GC_LOG_DIR: &str = "logs/gc";
+
+/// A well-known set of JVM flags for running a Minecraft server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum JvmPreset {
+  /// Aikar's tuned G1 flags, see <https://docs.papermc.io/paper/aikars-flags>.
+  /// Heaps over 12G use the large-heap variant.
+  Aikar,
+  /// The Z garbage collector, suited to very large heaps. Requires Java 17 or newer.
+  Zgc
+}
+
+impl JvmPreset {
+  /// The flags for this preset, given the server's maximum heap size in bytes (if known).
+  pub fn flags(self, max_memory: Option<u64>) -> Vec<String> {
+    match self {
+      JvmPreset::Aikar => {
+        let large = max_memory.is_some_and(|max_memory| max_memory > AIKAR_LARGE_HEAP);
+        let (new_size, max_new_size, region_size, reserve, occupancy) = match large {
+          true => (40, 50, "16M", 15, 20),
+          false => (30, 40, "8M", 20, 15)
+        };
+
+        vec![
+          "-XX:+UseG1GC".to_owned(),
+          "-XX:+ParallelRefProcEnabled".to_owned(),
+          "-XX:MaxGCPauseMillis=200".to_owned(),
+          "-XX:+UnlockExperimentalVMOptions".to_owned(),
+          "-XX:+DisableExplicitGC".to_owned(),
+          "-XX:+AlwaysPreTouch".to_owned(),
+          format!("-XX:G1NewSizePercent={}", new_size),
+          format!("-XX:G1MaxNewSizePercent={}", max_new_size),
+          format!("-XX:G1HeapRegionSize={}", region_size),
+          format!("-XX:G1ReservePercent={}", reserve),
+          "-XX:G1HeapWastePercent=5".to_owned(),
+          "-XX:G1MixedGCCountTarget=4".to_owned(),
+          format!("-XX:InitiatingHeapOccupancyPercent={}", occupancy),
+          "-XX:G1MixedGCLiveThresholdPercent=90".to_owned(),
+          "-XX:G1RSetUpdatingPauseTimePercent=5".to_owned(),
+          "-XX:SurvivorRatio=32".to_owned(),
+          "-XX:+PerfDisableSharedMem".to_owned(),
+          "-XX:MaxTenuringThreshold=1".to_owned(),
+          "-Dusing.aikars.flags=https://mcflags.emc.gs".to_owned(),
+          "-Daikars.new.flags=true".to_owned()
+        ]
+      },
+      JvmPreset::Zgc => vec![
+        "-XX:+UseZGC".to_owned(),
+        "-XX:+AlwaysPreTouch".to_owned(),
+        "-XX:+DisableExplicitGC".to_owned(),
+        "-XX:+PerfDisableSharedMem".to_owned()
+      ]
+    }
+  }
+}
+
+impl FromStr for JvmPreset {
+  type Err = UnknownJvmPreset;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_ascii_lowercase().as_str() {
+      "aikar" => Ok(JvmPreset::Aikar),
+      "zgc" => Ok(JvmPreset::Zgc),
+      _ => Err(UnknownJvmPreset(s.to_owned()))
+    }
+  }
+}
+
+impl fmt::Display for JvmPreset {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      JvmPreset::Aikar => "aikar",
+      JvmPreset::Zgc => "zgc"
+    })
+  }
+}
+
+/// The error returned when parsing a `JvmPreset` from an unrecognized name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownJvmPreset(pub String);
+
+impl fmt::Display for UnknownJvmPreset {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "unknown JVM preset `{}`, expected `aikar` or `zgc`", self.0)
+  }
+}
+
+impl std::error::Error for UnknownJvmPreset {}
+
+/// The flag enabling GC logging to `logs/gc`, rotating between 5 files of up to 1M each.
+/// Requires Java 9 or newer.
+pub(crate) fn gc_logging_flag() -> String {
+  format!("-Xlog:gc*:file={}/gc.log:time,uptime:filecount=5,filesize=1M", GC_LOG_DIR)
+}
@@ -1,10 +1,27 @@
 pub use async_trait::async_trait;
+pub use tokio_stream::{Stream, StreamExt};
 
+mod dispatch;
+mod handler;
+mod jvm;
+mod memory;
+#[cfg(unix)]
+mod pty;
 #[cfg(feature = "parsing")]
 mod parsing;
 mod puppet;
+mod text;
 
+pub use crate::dispatch::{DispatchOptions, Overflow};
+pub use crate::handler::{EventHandlerExt, Filter};
 #[cfg(feature = "parsing")]
-pub use crate::parsing::ConsoleLine;
-pub use crate::parsing::load_all;
-pub use crate::puppet::{EventHandler, Puppet, PuppetBuilder, NoHandler};
+pub use crate::handler::Only;
+pub use crate::jvm::{JvmPreset, UnknownJvmPreset};
+pub use crate::memory::{MemorySize, ParseMemorySizeError, system_memory};
+#[cfg(feature = "parsing")]
+pub use crate::parsing::{ConsoleLine, ConsoleLineKind, kind, load_all, server_info_message, strip_ansi_escapes};
+pub use crate::puppet::{EventHandler, Line, OutputStream, Puppet, PuppetBuilder, NoHandler, Subscription};
+pub use crate::puppet::{StopOptions, StopOutcome, StopStage};
+#[cfg(unix)]
+pub use crate::pty::{WindowSize, terminal_size};
+pub use crate::text::{Color, Text, TitleSlot, UnknownColor};
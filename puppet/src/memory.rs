@@ -0,0 +1,225 @@
+use std::fmt;
+use std::str::FromStr;
+
+const KILOBYTE: u64 = 1024;
+const MEGABYTE: u64 = 1024 * KILOBYTE;
+const GIGABYTE: u64 = 1024 * MEGABYTE;
+const TERABYTE: u64 = 1024 * GIGABYTE;
+
+/// An amount of memory, as passed to `-Xmx` or `-Xms`.
+/// Can be parsed from a number of bytes with an optional `K`, `M`, `G` or `T` suffix (such as `2G`),
+/// or from a percentage of the system's total memory (such as `50%`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemorySize {
+  /// An exact number of bytes.
+  Bytes(u64),
+  /// A percentage of the system's total memory.
+  Percent(f64)
+}
+
+impl MemorySize {
+  /// An amount of memory in kilobytes.
+  pub const fn kilobytes(n: u64) -> Self {
+    MemorySize::Bytes(n * KILOBYTE)
+  }
+
+  /// An amount of memory in megabytes.
+  pub const fn megabytes(n: u64) -> Self {
+    MemorySize::Bytes(n * MEGABYTE)
+  }
+
+  /// An amount of memory in gigabytes.
+  pub const fn gigabytes(n: u64) -> Self {
+    MemorySize::Bytes(n * GIGABYTE)
+  }
+
+  /// Resolve this amount to a number of bytes.
+  /// Percentages are rounded down to a whole megabyte (but no less than one), and resolve to `None`
+  /// if the system's total memory cannot be determined.
+  pub fn to_bytes(self) -> Option<u64> {
+    match self {
+      MemorySize::Bytes(bytes) => Some(bytes),
+      MemorySize::Percent(percent) => {
+        let bytes = system_memory()? as f64 * percent / 100.0;
+        Some((bytes as u64 / MEGABYTE).max(1) * MEGABYTE)
+      }
+    }
+  }
+
+  /// Resolve this amount to an argument for `-Xmx` or `-Xms`, such as `2G`.
+  pub(crate) fn to_jvm_arg(self) -> Option<String> {
+    self.to_bytes().map(|bytes| MemorySize::Bytes(bytes).to_string())
+  }
+}
+
+impl fmt::Display for MemorySize {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match *self {
+      MemorySize::Bytes(0) => write!(f, "0"),
+      MemorySize::Bytes(bytes) => {
+        // Use the largest unit that can represent the amount exactly
+        for &(unit, suffix) in &[(TERABYTE, "T"), (GIGABYTE, "G"), (MEGABYTE, "M"), (KILOBYTE, "K")] {
+          if bytes % unit == 0 {
+            return write!(f, "{}{}", bytes / unit, suffix);
+          };
+        };
+
+        write!(f, "{}", bytes)
+      },
+      MemorySize::Percent(percent) => write!(f, "{}%", percent)
+    }
+  }
+}
+
+impl FromStr for MemorySize {
+  type Err = ParseMemorySizeError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let error = || ParseMemorySizeError(s.to_owned());
+    let trimmed = s.trim();
+    if let Some(percent) = trimmed.strip_suffix('%') {
+      let percent = percent.trim().parse::<f64>().map_err(|_| error())?;
+      if percent > 0.0 && percent <= 100.0 {
+        Ok(MemorySize::Percent(percent))
+      } else {
+        Err(error())
+      }
+    } else {
+      let (digits, unit) = match trimmed.chars().last().ok_or_else(error)?.to_ascii_uppercase() {
+        'K' => (&trimmed[..trimmed.len() - 1], KILOBYTE),
+        'M' => (&trimmed[..trimmed.len() - 1], MEGABYTE),
+        'G' => (&trimmed[..trimmed.len() - 1], GIGABYTE),
+        'T' => (&trimmed[..trimmed.len() - 1], TERABYTE),
+        _ => (trimmed, 1)
+      };
+
+      // `str::parse::<u64>` would also accept a leading `+`
+      if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(error());
+      };
+
+      let bytes = digits.parse::<u64>().ok()
+        .and_then(|n| n.checked_mul(unit))
+        .filter(|&bytes| bytes > 0)
+        .ok_or_else(error)?;
+      Ok(MemorySize::Bytes(bytes))
+    }
+  }
+}
+
+/// The error returned when parsing a `MemorySize` from an invalid string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseMemorySizeError(pub String);
+
+impl fmt::Display for ParseMemorySizeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "invalid memory size `{}`, expected a non-zero number with an optional K, M, G or T suffix (such as `2G`), or a percentage (such as `50%`)", self.0)
+  }
+}
+
+impl std::error::Error for ParseMemorySizeError {}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for MemorySize {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for MemorySize {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    struct MemorySizeVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for MemorySizeVisitor {
+      type Value = MemorySize;
+
+      fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a memory size such as `2G` or `50%`")
+      }
+
+      fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<MemorySize, E> {
+        v.parse().map_err(E::custom)
+      }
+
+      fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<MemorySize, E> {
+        match v {
+          0 => Err(E::custom("memory size cannot be zero")),
+          v => Ok(MemorySize::Bytes(v))
+        }
+      }
+
+      fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<MemorySize, E> {
+        let v = u64::try_from(v).map_err(|_| E::custom("memory size cannot be negative"))?;
+        self.visit_u64(v)
+      }
+    }
+
+    deserializer.deserialize_any(MemorySizeVisitor)
+  }
+}
+
+/// The total amount of memory installed on this system in bytes, if it can be determined.
+/// This is currently only supported on Linux.
+pub fn system_memory() -> Option<u64> {
+  #[cfg(target_os = "linux")] {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kilobytes = meminfo.lines()
+      .find_map(|line| line.strip_prefix("MemTotal:"))?
+      .trim().strip_suffix("kB")?
+      .trim().parse::<u64>().ok()?;
+    Some(kilobytes * KILOBYTE)
+  }
+
+  #[cfg(not(target_os = "linux"))] {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse() {
+    assert_eq!("2G".parse(), Ok(MemorySize::gigabytes(2)));
+    assert_eq!(" 512m ".parse(), Ok(MemorySize::megabytes(512)));
+    assert_eq!("1536K".parse(), Ok(MemorySize::kilobytes(1536)));
+    assert_eq!("1T".parse(), Ok(MemorySize::Bytes(TERABYTE)));
+    assert_eq!("1000".parse(), Ok(MemorySize::Bytes(1000)));
+    assert_eq!("50%".parse(), Ok(MemorySize::Percent(50.0)));
+    assert_eq!("12.5 %".parse(), Ok(MemorySize::Percent(12.5)));
+    assert_eq!("100%".parse(), Ok(MemorySize::Percent(100.0)));
+  }
+
+  #[test]
+  fn parse_invalid() {
+    for s in ["", "G", "+2G", "-2G", "2.5G", "2X", "2GB", "101%", "-5%", "%", "99999999999T"] {
+      assert!(s.parse::<MemorySize>().is_err(), "{:?} should not parse", s);
+    };
+  }
+
+  #[test]
+  fn parse_zero() {
+    for s in ["0", "0K", "0G", "0%", "0.0%"] {
+      assert!(s.parse::<MemorySize>().is_err(), "{:?} should not parse", s);
+    };
+  }
+
+  #[test]
+  fn display() {
+    assert_eq!(MemorySize::gigabytes(2).to_string(), "2G");
+    assert_eq!(MemorySize::megabytes(1536).to_string(), "1536M");
+    assert_eq!(MemorySize::kilobytes(3).to_string(), "3K");
+    assert_eq!(MemorySize::Bytes(TERABYTE).to_string(), "1T");
+    assert_eq!(MemorySize::Bytes(1000).to_string(), "1000");
+    assert_eq!(MemorySize::Percent(12.5).to_string(), "12.5%");
+  }
+
+  #[test]
+  fn display_round_trips() {
+    for s in ["2G", "1536M", "3K", "1T", "1000", "50%", "12.5%"] {
+      assert_eq!(s.parse::<MemorySize>().unwrap().to_string(), s);
+    };
+  }
+}
@@ -21,6 +21,17 @@ pub enum ConsoleLine {
   StoppingServer,
   /// Server is complaining about being overloaded.
   Overloaded { ticks_behind: u32, ms_behind: u32 },
+  /// The JVM has run out of memory, for a reason such as `Java heap space` or `Metaspace`.
+  /// The server usually keeps running afterwards, but in a broken state.
+  OutOfMemory { reason: Option<String> },
+  /// The JVM has crashed with a fatal error, writing a report to an `hs_err_pid<pid>.log` file.
+  JvmFatalError { report: String },
+  /// The server could not bind to its port, usually because another server is already using it.
+  FailedToBindPort,
+  /// The server refused to start because its EULA has not been accepted in `eula.txt`.
+  EulaNotAccepted,
+  /// The server failed to load part of the world, which may be corrupted.
+  WorldCorruption { message: String },
   /// A player has 'moved wrongly' or 'moved too quickly'.
   PlayerMovedWrongly { username: String },
   /// A player has died.
@@ -45,6 +56,16 @@ impl ConsoleLine {
       Some(ConsoleLine::StoppingServer)
     } else if let Some((ticks_behind, ms_behind)) = match_overloaded(&line) {
       Some(ConsoleLine::Overloaded { ticks_behind, ms_behind })
+    } else if let Some(reason) = match_out_of_memory(&line) {
+      Some(ConsoleLine::OutOfMemory { reason })
+    } else if let Some(report) = match_jvm_fatal_error(&line) {
+      Some(ConsoleLine::JvmFatalError { report })
+    } else if match_failed_to_bind_port(&line) {
+      Some(ConsoleLine::FailedToBindPort)
+    } else if match_eula_not_accepted(&line) {
+      Some(ConsoleLine::EulaNotAccepted)
+    } else if let Some(message) = match_world_corruption(&line) {
+      Some(ConsoleLine::WorldCorruption { message })
     } else if let Some(username) = match_player_moved_wrongly(&line) {
       Some(ConsoleLine::PlayerMovedWrongly { username })
     } else if let Some(death_message) = match_player_died(&line) {
@@ -53,10 +74,8 @@ impl ConsoleLine {
       Some(ConsoleLine::ChatMessage { username, message })
     } else if let Some(username) = match_player_joined(&line) {
       Some(ConsoleLine::PlayerJoined { username })
-    } else if let Some(username) = match_player_left(&line) {
-      Some(ConsoleLine::PlayerLeft { username })
     } else {
-      None
+      match_player_left(&line).map(|username| ConsoleLine::PlayerLeft { username })
     }
   }
 }
@@ -69,6 +88,50 @@ impl FromStr for ConsoleLine {
   }
 }
 
+/// A marker for one variant of `ConsoleLine`, used for typed filtering with `EventHandlerExt::only`.
+pub trait ConsoleLineKind: Send + Sync + 'static {
+  /// Whether `line` is the variant this marker stands for.
+  fn matches(line: &ConsoleLine) -> bool;
+}
+
+macro_rules! console_line_kinds {
+  ($($variant:ident),* $(,)?) => {
+    /// Marker types standing for each variant of `ConsoleLine`.
+    pub mod kind {
+      use super::{ConsoleLine, ConsoleLineKind};
+
+      $(
+        #[doc = concat!("Matches `ConsoleLine::", stringify!($variant), "`.")]
+        #[derive(Debug, Clone, Copy)]
+        pub struct $variant;
+
+        impl ConsoleLineKind for $variant {
+          fn matches(line: &ConsoleLine) -> bool {
+            matches!(line, ConsoleLine::$variant { .. })
+          }
+        }
+      )*
+    }
+  };
+}
+
+console_line_kinds!(
+  DoneLoading,
+  StartingServer,
+  StoppingServer,
+  Overloaded,
+  OutOfMemory,
+  JvmFatalError,
+  FailedToBindPort,
+  EulaNotAccepted,
+  WorldCorruption,
+  PlayerMovedWrongly,
+  PlayerDied,
+  ChatMessage,
+  PlayerJoined,
+  PlayerLeft
+);
+
 macro_rules! regex {
   ($($arg:tt)*) => ({
     let rx = format!($($arg)*);
@@ -169,21 +232,39 @@ const DEATH_MESSAGES: &[&str] = &[
   "{1} was frozen to death by {2}"
 ];
 
-const MATCH_USERNAME: &str = r"[\w\d]{3,16}";
-const MATCH_INFO_LOG_CHAT: &str = r"^\[(?:[\d\w]{9} [\d:.]{12}|[\d:]{8})\] \[(?:Server thread/INFO|Async Chat Thread - #\d+/INFO)\](?: \[[\w.]+/?\])?:";
-const MATCH_INFO_LOG: &str = r"^\[(?:[\d\w]{9} [\d:.]{12}|[\d:]{8})\] \[Server thread/INFO\](?: \[[\w.]+/?\])?:";
-const MATCH_WARN_LOG: &str = r"^\[(?:[\d\w]{9} [\d:.]{12}|[\d:]{8})\] \[Server thread/WARN\](?: \[[\w.]+/?\])?:";
+const MATCH_USERNAME: &str = r"[A-Za-z0-9_]{3,16}";
+// Vanilla logs lines such as `[12:00:00] [Server thread/INFO]:`, while Paper and Spigot log them as
+// `[12:00:00 INFO]:`, leaving out the thread
+const MATCH_INFO_LOG_CHAT: &str = r"^(?:\[(?:[\d\w]{9} [\d:.]{12}|[\d:]{8})\] \[(?:Server thread/INFO|Async Chat Thread - #\d+/INFO)\](?: \[[\w.]+/?\])?|\[[\d:]{8} INFO\]):";
+const MATCH_INFO_LOG: &str = r"^(?:\[(?:[\d\w]{9} [\d:.]{12}|[\d:]{8})\] \[Server thread/INFO\](?: \[[\w.]+/?\])?|\[[\d:]{8} INFO\]):";
+const MATCH_WARN_LOG: &str = r"^(?:\[(?:[\d\w]{9} [\d:.]{12}|[\d:]{8})\] \[Server thread/WARN\](?: \[[\w.]+/?\])?|\[[\d:]{8} WARN\]):";
+// Startup and world loading happen on other threads too, such as `main` or `Worker-Main-1`
+const MATCH_ANY_INFO_LOG: &str = r"^(?:\[(?:[\d\w]{9} [\d:.]{12}|[\d:]{8})\] \[[^\]]+/INFO\](?: \[[\w.]+/?\])?|\[[\d:]{8} INFO\]):";
+const MATCH_ANY_ERROR_LOG: &str = r"^(?:\[(?:[\d\w]{9} [\d:.]{12}|[\d:]{8})\] \[[^\]]+/(?:WARN|ERROR|FATAL)\](?: \[[\w.]+/?\])?|\[[\d:]{8} (?:WARN|ERROR|FATAL)\]):";
+// Excludes warnings, which servers also log for chunks they recover from
+const MATCH_ANY_SEVERE_LOG: &str = r"^(?:\[(?:[\d\w]{9} [\d:.]{12}|[\d:]{8})\] \[[^\]]+/(?:ERROR|FATAL)\](?: \[[\w.]+/?\])?|\[[\d:]{8} (?:ERROR|FATAL)\]):";
 
 lazy_static!{
   static ref RX_DONE_LOADING: Regex = regex!(r#"{} Done \((\d+\.\d+)s\)! For help, type "help""#, MATCH_INFO_LOG);
   static ref RX_STARTING_SERVER: Regex = regex!(r"{} Starting minecraft server version (.+)", MATCH_INFO_LOG);
   static ref RX_STOPPING_SERVER: Regex = regex!(r"{} Stopping server", MATCH_INFO_LOG);
   static ref RX_OVERLOADED: Regex = regex!(r"{} Can't keep up! Is the server overloaded\? Running (\d+)ms or (\d+) ticks behind", MATCH_WARN_LOG);
-  static ref RX_PLAYER_MOVED_WRONGLY: Regex = regex!(r"{} (?:({u})|.+ \(vehicle of ({u})\)) moved (?:too quickly|wrongly)!.*", u = MATCH_USERNAME);
+  // Stack traces are printed without the log prefix
+  static ref RX_OUT_OF_MEMORY: Regex = regex!(r#"^(?:{} |Exception in thread ".*" |Caused by: )?java\.lang\.OutOfMemoryError(?:: (.+))?"#, MATCH_ANY_ERROR_LOG);
+  static ref RX_JVM_FATAL_ERROR: Regex = regex!(r"^#\s+(\S*hs_err_pid\d+\.log)");
+  static ref RX_FAILED_TO_BIND_PORT: Regex = regex!(r"{} \*\*\*\* FAILED TO BIND TO PORT!", MATCH_ANY_ERROR_LOG);
+  static ref RX_EULA_NOT_ACCEPTED: Regex = regex!(r"{} You need to agree to the EULA in order to run the server", MATCH_ANY_INFO_LOG);
+  // Broken chunks only count as errors, while a broken `level.dat` is always worth acting on
+  static ref RX_WORLD_CORRUPTION: Regex = regex!(
+    r"{} ((?:Couldn't|Failed to) (?:load|read) chunk.*|Region file .*(?:truncated|corrupt).*|.*[Cc]orrupt(?:ed)? chunk.*)|{} ((?:Exception reading|Failed to (?:load|read)) .*level\.dat.*)",
+    MATCH_ANY_SEVERE_LOG, MATCH_ANY_ERROR_LOG
+  );
+  static ref RX_PLAYER_MOVED_WRONGLY: Regex = regex!(r"{} (?:({u})|.+ \(vehicle of ({u})\)) moved (?:too quickly|wrongly)!.*", MATCH_WARN_LOG, u = MATCH_USERNAME);
   static ref RX_PLAYER_DIED: Regex = regex!(r"{} ({})", MATCH_INFO_LOG, match_death_messages());
   static ref RX_CHAT_MESSAGE: Regex = regex!(r"{} <({})> (.+)", MATCH_INFO_LOG_CHAT, MATCH_USERNAME);
   static ref RX_PLAYER_JOINED: Regex = regex!(r"{} ({}) joined the game", MATCH_INFO_LOG, MATCH_USERNAME);
   static ref RX_PLAYER_LEFT: Regex = regex!(r"{} ({}) left the game", MATCH_INFO_LOG, MATCH_USERNAME);
+  static ref RX_SERVER_INFO_MESSAGE: Regex = regex!(r"{} (.*)", MATCH_INFO_LOG);
 }
 
 fn match_death_messages() -> String {
@@ -200,11 +281,17 @@ pub fn load_all() {
     &*RX_STARTING_SERVER,
     &*RX_STOPPING_SERVER,
     &*RX_OVERLOADED,
+    &*RX_OUT_OF_MEMORY,
+    &*RX_JVM_FATAL_ERROR,
+    &*RX_FAILED_TO_BIND_PORT,
+    &*RX_EULA_NOT_ACCEPTED,
+    &*RX_WORLD_CORRUPTION,
     &*RX_PLAYER_MOVED_WRONGLY,
     &*RX_PLAYER_DIED,
     &*RX_CHAT_MESSAGE,
     &*RX_PLAYER_JOINED,
-    &*RX_PLAYER_LEFT
+    &*RX_PLAYER_LEFT,
+    &*RX_SERVER_INFO_MESSAGE
   ];
 }
 
@@ -235,13 +322,39 @@ fn match_stopping_server(line: &str) -> bool {
 
 fn match_overloaded(line: &str) -> Option<(u32, u32)> {
   let captures = RX_OVERLOADED.captures(line)?;
-  let ticks_behind = captures.get(1).unwrap().as_str();
-  let ticks_behind = ticks_behind.parse::<u32>().ok()?;
-  let ms_behind = captures.get(2).unwrap().as_str();
+  let ms_behind = captures.get(1).unwrap().as_str();
   let ms_behind = ms_behind.parse::<u32>().ok()?;
+  let ticks_behind = captures.get(2).unwrap().as_str();
+  let ticks_behind = ticks_behind.parse::<u32>().ok()?;
   Some((ticks_behind, ms_behind))
 }
 
+fn match_out_of_memory(line: &str) -> Option<Option<String>> {
+  let captures = RX_OUT_OF_MEMORY.captures(line)?;
+  let reason = captures.get(1).map(|reason| reason.as_str().trim().to_owned());
+  Some(reason)
+}
+
+fn match_jvm_fatal_error(line: &str) -> Option<String> {
+  let captures = RX_JVM_FATAL_ERROR.captures(line)?;
+  let report = captures.get(1).unwrap().as_str();
+  Some(report.to_owned())
+}
+
+fn match_failed_to_bind_port(line: &str) -> bool {
+  RX_FAILED_TO_BIND_PORT.is_match(line)
+}
+
+fn match_eula_not_accepted(line: &str) -> bool {
+  RX_EULA_NOT_ACCEPTED.is_match(line)
+}
+
+fn match_world_corruption(line: &str) -> Option<String> {
+  let captures = RX_WORLD_CORRUPTION.captures(line)?;
+  let message = captures.get(1).or_else(|| captures.get(2)).unwrap().as_str();
+  Some(message.to_owned())
+}
+
 fn match_player_moved_wrongly(line: &str) -> Option<String> {
   let captures = RX_PLAYER_MOVED_WRONGLY.captures(line)?;
   let username = Option::or(captures.get(1), captures.get(2)).unwrap().as_str();
@@ -275,8 +388,20 @@ fn match_player_left(line: &str) -> Option<String> {
 
 
 
-fn strip_ansi_escapes(buf: &str) -> String {
-  let mut performer = Performer { buf: String::new() };
+/// The message of a line logged by the server thread at the `INFO` level, without the timestamp and thread,
+/// such as `There are 0 of a max of 20 players online:`. Lines logged by players (such as chat) start with
+/// their name, so a message can be trusted to come from the server by checking how it starts.
+pub fn server_info_message(line: &str) -> Option<String> {
+  let line = strip_ansi_escapes(line);
+  let captures = RX_SERVER_INFO_MESSAGE.captures(&line)?;
+  Some(captures.get(1).unwrap().as_str().to_owned())
+}
+
+/// Removes ANSI escape sequences (colors, cursor movement, window titles) from a line of console output.
+/// Carriage returns are treated as a return to the start of the line, so that text a console redraws over
+/// (such as JLine's `> ` prompt) is removed as well.
+pub fn strip_ansi_escapes(buf: &str) -> String {
+  let mut performer = Performer { buf: String::new(), carriage_return: false };
   let mut parser = Parser::new();
   for &b in buf.as_bytes().iter() {
     parser.advance(&mut performer, b);
@@ -285,19 +410,174 @@ fn strip_ansi_escapes(buf: &str) -> String {
   performer.buf
 }
 
-#[repr(transparent)]
 struct Performer {
-  buf: String
+  buf: String,
+  /// Whether a carriage return was seen, meaning the next printed character overwrites the current line.
+  carriage_return: bool
 }
 
 impl Perform for Performer {
   fn print(&mut self, c: char) {
+    if self.carriage_return {
+      self.carriage_return = false;
+      let line_start = self.buf.rfind('\n').map_or(0, |i| i + 1);
+      self.buf.truncate(line_start);
+    };
+
     self.buf.push(c);
   }
 
   fn execute(&mut self, byte: u8) {
-    if byte == b'\n' {
-      self.buf.push('\n');
+    match byte {
+      b'\n' => {
+        self.carriage_return = false;
+        self.buf.push('\n');
+      },
+      b'\r' => self.carriage_return = true,
+      _ => ()
+    };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn every_regex_compiles() {
+    load_all();
+  }
+
+  #[test]
+  fn server_info_messages() {
+    assert_eq!(
+      server_info_message("[12:00:00] [Server thread/INFO]: There are 0 of a max of 20 players online: ").as_deref(),
+      Some("There are 0 of a max of 20 players online: ")
+    );
+    assert_eq!(
+      server_info_message("[12:00:00] [Server thread/INFO]: <Notch> There are 0 players online").as_deref(),
+      Some("<Notch> There are 0 players online")
+    );
+    assert_eq!(server_info_message("[12:00:00] [Async Chat Thread - #0/INFO]: <Notch> There are"), None);
+    assert_eq!(server_info_message("[12:00:00] [Server thread/WARN]: There are"), None);
+    assert_eq!(server_info_message("There are 0 of a max of 20 players online:"), None);
+  }
+
+  #[test]
+  fn parses_chat_after_death_messages() {
+    assert_eq!(
+      ConsoleLine::parse_from("[12:00:00] [Server thread/INFO]: <Notch> hello"),
+      Some(ConsoleLine::ChatMessage { username: "Notch".to_owned(), message: "hello".to_owned() })
+    );
+  }
+
+  #[test]
+  fn paper_lines() {
+    assert_eq!(
+      ConsoleLine::parse_from(r#"[12:00:00 INFO]: Done (12.345s)! For help, type "help""#),
+      Some(ConsoleLine::DoneLoading { time: 12.345 })
+    );
+    assert_eq!(ConsoleLine::parse_from("[12:00:00 INFO]: Stopping server"), Some(ConsoleLine::StoppingServer));
+    assert_eq!(
+      ConsoleLine::parse_from("[12:00:00 INFO]: Notch joined the game"),
+      Some(ConsoleLine::PlayerJoined { username: "Notch".to_owned() })
+    );
+    assert_eq!(
+      ConsoleLine::parse_from("[12:00:00 INFO]: <Notch> hello"),
+      Some(ConsoleLine::ChatMessage { username: "Notch".to_owned(), message: "hello".to_owned() })
+    );
+    assert_eq!(
+      ConsoleLine::parse_from("[12:00:00 WARN]: Can't keep up! Is the server overloaded? Running 2500ms or 50 ticks behind"),
+      Some(ConsoleLine::Overloaded { ticks_behind: 50, ms_behind: 2500 })
+    );
+    assert_eq!(
+      server_info_message("[12:00:00 INFO]: There are 0 of a max of 20 players online: ").as_deref(),
+      Some("There are 0 of a max of 20 players online: ")
+    );
+    // A chat message cannot pass for a server message, as it starts with the player's name
+    assert_eq!(ConsoleLine::parse_from(r#"[12:00:00 INFO]: <Notch> Done (1.000s)! For help, type "help""#),
+      Some(ConsoleLine::ChatMessage { username: "Notch".to_owned(), message: r#"Done (1.000s)! For help, type "help""#.to_owned() }));
+  }
+
+  #[test]
+  fn world_corruption_levels() {
+    let corruption = |line| match ConsoleLine::parse_from(line) {
+      Some(ConsoleLine::WorldCorruption { message }) => Some(message),
+      _ => None
+    };
+    assert_eq!(
+      corruption("[12:00:00] [Worker-Main-3/ERROR]: Couldn't load chunk [12, -4]").as_deref(),
+      Some("Couldn't load chunk [12, -4]")
+    );
+    assert_eq!(
+      corruption("[12:00:00] [Server thread/FATAL]: Region file ./world/region/r.0.0.mca is truncated").as_deref(),
+      Some("Region file ./world/region/r.0.0.mca is truncated")
+    );
+    // Servers warn about chunks they recover from
+    assert_eq!(corruption("[12:00:00] [Worker-Main-3/WARN]: Couldn't load chunk [12, -4]"), None);
+    assert_eq!(
+      corruption("[12:00:00] [main/WARN]: Failed to load level.dat from ./world").as_deref(),
+      Some("Failed to load level.dat from ./world")
+    );
+    assert_eq!(
+      corruption("[12:00:00] [main/ERROR]: Exception reading ./world/level.dat").as_deref(),
+      Some("Exception reading ./world/level.dat")
+    );
+    assert_eq!(corruption("[12:00:00] [main/INFO]: Failed to load level.dat from ./world"), None);
+  }
+
+  #[test]
+  fn server_errors() {
+    assert_eq!(
+      ConsoleLine::parse_from(r#"Exception in thread "Server thread" java.lang.OutOfMemoryError: Java heap space"#),
+      Some(ConsoleLine::OutOfMemory { reason: Some("Java heap space".to_owned()) })
+    );
+    assert_eq!(
+      ConsoleLine::parse_from("[12:00:00] [Server thread/ERROR]: java.lang.OutOfMemoryError: Metaspace"),
+      Some(ConsoleLine::OutOfMemory { reason: Some("Metaspace".to_owned()) })
+    );
+    assert_eq!(
+      ConsoleLine::parse_from("Caused by: java.lang.OutOfMemoryError"),
+      Some(ConsoleLine::OutOfMemory { reason: None })
+    );
+    assert_eq!(
+      ConsoleLine::parse_from("# /srv/minecraft/hs_err_pid1234.log"),
+      Some(ConsoleLine::JvmFatalError { report: "/srv/minecraft/hs_err_pid1234.log".to_owned() })
+    );
+    assert_eq!(
+      ConsoleLine::parse_from("[12:00:00] [Server thread/WARN]: **** FAILED TO BIND TO PORT!"),
+      Some(ConsoleLine::FailedToBindPort)
+    );
+    assert_eq!(
+      ConsoleLine::parse_from("[12:00:00 WARN]: **** FAILED TO BIND TO PORT!"),
+      Some(ConsoleLine::FailedToBindPort)
+    );
+    assert_eq!(
+      ConsoleLine::parse_from("[12:00:00] [main/INFO]: You need to agree to the EULA in order to run the server. Go to eula.txt for more info."),
+      Some(ConsoleLine::EulaNotAccepted)
+    );
+    assert_eq!(
+      ConsoleLine::parse_from("[12:00:00 INFO]: You need to agree to the EULA in order to run the server. Go to eula.txt for more info."),
+      Some(ConsoleLine::EulaNotAccepted)
+    );
+  }
+
+  #[test]
+  fn chat_does_not_look_like_server_errors() {
+    let lines = [
+      "[12:00:00] [Server thread/INFO]: <Notch> java.lang.OutOfMemoryError: Java heap space",
+      r#"[12:00:00] [Server thread/INFO]: <Notch> Exception in thread "Server thread" java.lang.OutOfMemoryError"#,
+      "[12:00:00] [Server thread/INFO]: <Notch> # /srv/minecraft/hs_err_pid1234.log",
+      "[12:00:00] [Server thread/INFO]: <Notch> **** FAILED TO BIND TO PORT!",
+      "[12:00:00] [Server thread/INFO]: <Notch> You need to agree to the EULA in order to run the server",
+      "[12:00:00 INFO]: <Notch> **** FAILED TO BIND TO PORT!",
+      "[12:00:00] [Server thread/INFO]: [Server] **** FAILED TO BIND TO PORT!"
+    ];
+    for line in lines {
+      assert!(
+        matches!(ConsoleLine::parse_from(line), Some(ConsoleLine::ChatMessage { .. }) | None),
+        "{:?} was parsed as {:?}", line, ConsoleLine::parse_from(line)
+      );
     };
   }
 }
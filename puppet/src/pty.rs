@@ -0,0 +1,93 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+
+/// The size of a terminal window, in character cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowSize {
+  pub cols: u16,
+  pub rows: u16
+}
+
+impl WindowSize {
+  fn to_winsize(self) -> libc::winsize {
+    libc::winsize { ws_row: self.rows, ws_col: self.cols, ws_xpixel: 0, ws_ypixel: 0 }
+  }
+}
+
+impl Default for WindowSize {
+  fn default() -> Self {
+    WindowSize { cols: 80, rows: 24 }
+  }
+}
+
+/// Both ends of a newly opened pseudo-terminal.
+pub(crate) struct Pty {
+  pub(crate) master: File,
+  pub(crate) slave: File
+}
+
+impl Pty {
+  /// Opens a new pseudo-terminal with the given window size.
+  /// Echo is disabled so that commands written to the master are not repeated back in the output.
+  pub(crate) fn open(size: WindowSize) -> io::Result<Pty> {
+    let (mut master, mut slave) = (-1, -1);
+    let mut winsize = size.to_winsize();
+    check(unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null_mut(), ptr::addr_of_mut!(winsize)) })?;
+    let pty = unsafe { Pty { master: File::from_raw_fd(master), slave: File::from_raw_fd(slave) } };
+
+    // Neither end should leak into the child beyond its standard streams
+    set_cloexec(pty.master.as_raw_fd())?;
+    set_cloexec(pty.slave.as_raw_fd())?;
+
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    check(unsafe { libc::tcgetattr(pty.slave.as_raw_fd(), &mut termios) })?;
+    termios.c_lflag &= !(libc::ECHO | libc::ECHONL);
+    check(unsafe { libc::tcsetattr(pty.slave.as_raw_fd(), libc::TCSANOW, &termios) })?;
+
+    Ok(pty)
+  }
+}
+
+/// To be run in the child before `exec`, making the pseudo-terminal on its stdin its controlling terminal.
+pub(crate) fn make_controlling_terminal() -> io::Result<()> {
+  check(unsafe { libc::setsid() })?;
+  check(unsafe { libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY as _, 0) })?;
+  Ok(())
+}
+
+/// Sets the window size of a pseudo-terminal through its master end.
+pub(crate) fn set_window_size(master: &File, size: WindowSize) -> io::Result<()> {
+  let winsize = size.to_winsize();
+  check(unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ as _, &winsize) })?;
+  Ok(())
+}
+
+/// The window size of the terminal this process' stdout is attached to, if any.
+pub fn terminal_size() -> Option<WindowSize> {
+  let mut winsize = unsafe { std::mem::zeroed::<libc::winsize>() };
+  match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ as _, &mut winsize) } {
+    0 if winsize.ws_col > 0 && winsize.ws_row > 0 => Some(WindowSize { cols: winsize.ws_col, rows: winsize.ws_row }),
+    _ => None
+  }
+}
+
+/// Whether an error from reading the master end means that the child has closed the terminal.
+/// Linux reports this as `EIO` rather than end-of-file.
+pub(crate) fn is_closed(err: &io::Error) -> bool {
+  err.raw_os_error() == Some(libc::EIO)
+}
+
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+  let flags = check(unsafe { libc::fcntl(fd, libc::F_GETFD) })?;
+  check(unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) })?;
+  Ok(())
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+  match result {
+    -1 => Err(io::Error::last_os_error()),
+    result => Ok(result)
+  }
+}
@@ -1,18 +1,65 @@
 use async_trait::async_trait;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::process::{Command, Child, ChildStdout, ChildStdin};
-use tokio::sync::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::{Command, Child};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{Mutex, MutexGuard, Notify};
+use tokio::time::Instant;
+use tokio_stream::Stream;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::pin::Pin;
 use std::process::{Stdio, ExitStatus};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(feature = "parsing")]
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
 use std::io;
 
+use crate::dispatch::{DispatchOptions, HandlerQueue, Overflow, isolate};
+use crate::jvm::{self, JvmPreset};
+use crate::memory::MemorySize;
+#[cfg(unix)]
+use crate::pty::{self, Pty, WindowSize};
+#[cfg(feature = "parsing")]
+use crate::parsing::ConsoleLine;
+use crate::text::{Text, TitleSlot};
+
+/// The memory used for both `-Xmx` and `-Xms` when not set.
+const DEFAULT_MEMORY: MemorySize = MemorySize::gigabytes(2);
+
+/// One of the server's output streams, either a pipe or the master end of a pseudo-terminal.
+type ChildOutput = Box<dyn AsyncRead + Send + Unpin>;
+/// The server's input stream, either a pipe or the master end of a pseudo-terminal.
+type ChildInput = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// How many console lines may be buffered for a slow subscriber before it starts missing lines.
+const LINE_CHANNEL_CAPACITY: usize = 1024;
+
 /// A struct for configuring and instantiating a Puppet.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct PuppetBuilder {
+  launch: Option<(OsString, Vec<OsString>)>,
+  java: Option<PathBuf>,
   jar_path: Option<PathBuf>,
-  max_memory: Option<String>,
-  min_memory: Option<String>
+  max_memory: Option<MemorySize>,
+  min_memory: Option<MemorySize>,
+  jvm_preset: Option<JvmPreset>,
+  gc_logging: bool,
+  jvm_args: Vec<OsString>,
+  server_args: Vec<OsString>,
+  envs: Vec<(OsString, OsString)>,
+  current_dir: Option<PathBuf>,
+  pty: bool,
+  command_prefix: Option<String>,
+  dispatch: DispatchOptions
 }
 
 impl PuppetBuilder {
@@ -21,63 +68,267 @@ impl PuppetBuilder {
     PuppetBuilder::default()
   }
 
+  /// Launch an arbitrary console-driven program instead of a Java server, such as a Bedrock Dedicated Server,
+  /// a proxy or a launcher script. In this mode, only the environment, working directory and dispatch
+  /// options are used, while the Java-specific options (such as the jar path and memory) are ignored.
+  pub fn command<I>(mut self, program: impl Into<OsString>, args: I) -> Self
+  where I: IntoIterator, I::Item: Into<OsString> {
+    self.launch = Some((program.into(), args.into_iter().map(Into::into).collect()));
+    self
+  }
+
+  /// Set the path to the `java` executable.
+  /// By default, `$JAVA_HOME/bin/java` is used if `JAVA_HOME` is set, otherwise `java` is looked up in the `PATH`.
+  pub fn java(mut self, java: impl AsRef<Path>) -> Self {
+    self.java = Some(java.as_ref().to_owned());
+    self
+  }
+
   /// Set the path to the server `.jar` file.
-  /// NOTE: The server will use the current working directory as its own working directory,
-  /// which may cause unintended behavior if set to a file outside of the current directory.
+  /// A relative path is resolved from the server's working directory, see `PuppetBuilder::current_dir`.
   pub fn jar_path(mut self, jar_path: impl AsRef<Path>) -> Self {
     self.jar_path = Some(jar_path.as_ref().to_owned());
     self
   }
 
-  /// Set the maximum memory (`-Xmx`) for the server.
-  /// Examples of valid values are `2G`, `1024K`.
-  pub fn max_memory(mut self, max_memory: impl AsRef<str>) -> Self {
-    self.max_memory = Some(max_memory.as_ref().to_owned());
+  /// Set the maximum memory (`-Xmx`) for the server. Defaults to `2G`.
+  pub fn max_memory(mut self, max_memory: MemorySize) -> Self {
+    self.max_memory = Some(max_memory);
+    self
+  }
+
+  /// Set the minimum memory (`-Xms`) for the server. Defaults to `2G`.
+  pub fn min_memory(mut self, min_memory: MemorySize) -> Self {
+    self.min_memory = Some(min_memory);
+    self
+  }
+
+  /// Use a well-known set of JVM flags, such as Aikar's flags.
+  /// Arguments added with `PuppetBuilder::jvm_arg` are passed after the preset, so they can override it.
+  pub fn jvm_preset(mut self, jvm_preset: JvmPreset) -> Self {
+    self.jvm_preset = Some(jvm_preset);
     self
   }
 
-  /// Set the minimum memory (`-Xms`) for the server.
-  /// Examples of valid values are `2G`, `1024K`.
-  pub fn min_memory(mut self, min_memory: impl AsRef<str>) -> Self {
-    self.min_memory = Some(min_memory.as_ref().to_owned());
+  /// Enable GC logging, written to the `logs/gc` directory in the server's working directory
+  /// and rotated between 5 files of up to 1M each. Requires Java 9 or newer.
+  pub fn gc_logging(mut self, gc_logging: bool) -> Self {
+    self.gc_logging = gc_logging;
+    self
+  }
+
+  /// Add an argument to be passed to the JVM, such as `-XX:+UseG1GC`.
+  pub fn jvm_arg(mut self, arg: impl Into<OsString>) -> Self {
+    self.jvm_args.push(arg.into());
+    self
+  }
+
+  /// Add several arguments to be passed to the JVM.
+  pub fn jvm_args<I>(mut self, args: I) -> Self
+  where I: IntoIterator, I::Item: Into<OsString> {
+    self.jvm_args.extend(args.into_iter().map(Into::into));
+    self
+  }
+
+  /// Add an argument to be passed to the server, after `nogui`, such as `--forceUpgrade`.
+  pub fn server_arg(mut self, arg: impl Into<OsString>) -> Self {
+    self.server_args.push(arg.into());
+    self
+  }
+
+  /// Add several arguments to be passed to the server, after `nogui`.
+  pub fn server_args<I>(mut self, args: I) -> Self
+  where I: IntoIterator, I::Item: Into<OsString> {
+    self.server_args.extend(args.into_iter().map(Into::into));
+    self
+  }
+
+  /// Set an environment variable for the server's process.
+  pub fn env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+    self.envs.push((key.into(), value.into()));
+    self
+  }
+
+  /// Set several environment variables for the server's process.
+  pub fn envs<I, K, V>(mut self, vars: I) -> Self
+  where I: IntoIterator<Item = (K, V)>, K: Into<OsString>, V: Into<OsString> {
+    self.envs.extend(vars.into_iter().map(|(key, value)| (key.into(), value.into())));
+    self
+  }
+
+  /// Set the working directory of the server, where it will look for and create its files.
+  /// Defaults to the current working directory.
+  pub fn current_dir(mut self, dir: impl AsRef<Path>) -> Self {
+    self.current_dir = Some(dir.as_ref().to_owned());
+    self
+  }
+
+  /// Run the server attached to a pseudo-terminal instead of pipes, so that it sees a real terminal.
+  /// This lets JLine-based consoles (such as Paper's and Forge's) enable colors and tab completion.
+  /// Stdout and stderr are merged into one stream in this mode, and the terminal's window size follows
+  /// this process' terminal. Only supported on Unix platforms, elsewhere `finish` will return an error.
+  pub fn pty(mut self, pty: bool) -> Self {
+    self.pty = pty;
+    self
+  }
+
+  /// Intercept lines typed into this process' stdin that start with `prefix` (such as `!pm `),
+  /// dispatching them to `EventHandler::wrapper_command` instead of sending them to the server.
+  pub fn command_prefix(mut self, prefix: impl Into<String>) -> Self {
+    self.command_prefix = Some(prefix.into());
+    self
+  }
+
+  /// Set how many lines may be queued up for the event handler before the overflow policy applies.
+  /// Defaults to 1024.
+  pub fn queue_capacity(mut self, capacity: usize) -> Self {
+    self.dispatch.capacity = capacity;
+    self
+  }
+
+  /// Set what happens to new lines when the event handler's queue is full.
+  /// Defaults to `Overflow::DropOldest`.
+  pub fn overflow(mut self, overflow: Overflow) -> Self {
+    self.dispatch.overflow = overflow;
     self
   }
 
   /// Launch the server and return a handle (`Puppet`) for it.
   pub fn finish(self) -> io::Result<Puppet> {
-    let xmx = self.max_memory.unwrap_or_else(|| "2g".to_owned());
-    let xms = self.min_memory.unwrap_or_else(|| "2g".to_owned());
-    let jar = self.jar_path.unwrap_or_else(|| PathBuf::from("minecraft_server.jar"));
+    let mut command = match &self.launch {
+      Some((program, args)) => {
+        let mut command = Command::new(program);
+        command.args(args);
+        command
+      },
+      None => self.java_command()?
+    };
 
-    let child = Command::new("java")
+    command.envs(self.envs);
+    if let Some(current_dir) = self.current_dir {
+      command.current_dir(current_dir);
+    };
+
+    let puppet = match self.pty {
+      true => spawn_pty(command, self.dispatch)?,
+      false => {
+        command
+          .stdout(Stdio::piped())
+          .stderr(Stdio::piped())
+          .stdin(Stdio::piped());
+        #[cfg(unix)]
+        unsafe { command.pre_exec(new_process_group) };
+        Puppet::from_child_with(command.spawn()?, self.dispatch)
+      }
+    };
+
+    // A new session (as in PTY mode) also starts a new process group
+    Ok(Puppet { command_prefix: self.command_prefix, process_group: cfg!(unix), ..puppet })
+  }
+
+  /// Builds the command for launching a Java server from the jar path, memory and JVM options.
+  fn java_command(&self) -> io::Result<Command> {
+    let max_memory = self.max_memory.unwrap_or(DEFAULT_MEMORY);
+    let xmx = max_memory.to_jvm_arg().ok_or_else(unknown_system_memory)?;
+    let xms = self.min_memory.unwrap_or(DEFAULT_MEMORY)
+      .to_jvm_arg().ok_or_else(unknown_system_memory)?;
+    let jar = self.jar_path.clone().unwrap_or_else(|| PathBuf::from("minecraft_server.jar"));
+    let java = self.java.clone().unwrap_or_else(default_java);
+    let preset_args = match self.jvm_preset {
+      Some(jvm_preset) => jvm_preset.flags(max_memory.to_bytes()),
+      None => Vec::new()
+    };
+
+    let mut command = Command::new(java);
+    command
       .arg(format!("-Xmx{}", xmx))
       .arg(format!("-Xms{}", xms))
+      .args(preset_args);
+    if self.gc_logging {
+      // The JVM will not create the log directory by itself
+      let current_dir = self.current_dir.clone().unwrap_or_default();
+      fs::create_dir_all(current_dir.join(jvm::GC_LOG_DIR))?;
+      command.arg(jvm::gc_logging_flag());
+    };
+
+    command
+      .args(&self.jvm_args)
       .arg("-jar")
       .arg(jar)
       .arg("nogui")
-      .stdout(Stdio::piped())
-      .stdin(Stdio::piped())
-      .spawn()?;
-    Ok(Puppet::from_child(child))
+      .args(&self.server_args);
+    Ok(command)
   }
 }
 
-impl Default for PuppetBuilder {
-  fn default() -> PuppetBuilder {
-    PuppetBuilder {
-      jar_path: None,
-      max_memory: None,
-      min_memory: None
-    }
+fn unknown_system_memory() -> io::Error {
+  io::Error::new(io::ErrorKind::Unsupported, "memory given as a percentage, but the system's memory could not be determined")
+}
+
+/// Finds `java` in `JAVA_HOME` if it is set, falling back to whichever `java` is in the `PATH`.
+fn default_java() -> PathBuf {
+  let java = if cfg!(windows) { "java.exe" } else { "java" };
+  env::var_os("JAVA_HOME")
+    .map(|java_home| PathBuf::from(java_home).join("bin").join(java))
+    .filter(|java| java.is_file())
+    .unwrap_or_else(|| PathBuf::from("java"))
+}
+
+/// Makes the server's process the leader of a new process group, so that it can be signalled
+/// along with any processes it starts, such as the JVM started by a launcher script.
+/// This also keeps it from receiving signals meant for this process' group, such as the terminal's Ctrl-C.
+#[cfg(unix)]
+fn new_process_group() -> io::Result<()> {
+  match unsafe { libc::setpgid(0, 0) } {
+    0 => Ok(()),
+    _ => Err(io::Error::last_os_error())
   }
 }
 
+/// Spawns the command attached to a new pseudo-terminal.
+#[cfg(unix)]
+fn spawn_pty(mut command: Command, dispatch: DispatchOptions) -> io::Result<Puppet> {
+  let pty = Pty::open(pty::terminal_size().unwrap_or_default())?;
+  command
+    .stdin(pty.slave.try_clone()?)
+    .stdout(pty.slave.try_clone()?)
+    .stderr(pty.slave);
+  unsafe { command.pre_exec(pty::make_controlling_terminal) };
+  let child = command.spawn()?;
+  // The command still holds copies of the slave end, which must be closed for the
+  // master end to notice when the server exits
+  drop(command);
+
+  let output = tokio::fs::File::from_std(pty.master.try_clone()?);
+  let input = tokio::fs::File::from_std(pty.master.try_clone()?);
+  Ok(Puppet::from_parts(child, Box::new(output), Box::new(input), None, Some(pty.master), dispatch))
+}
+
+#[cfg(not(unix))]
+fn spawn_pty(_command: Command, _dispatch: DispatchOptions) -> io::Result<Puppet> {
+  Err(io::Error::new(io::ErrorKind::Unsupported, "pseudo-terminals are only supported on unix"))
+}
+
 /// A handle for a Minecraft server's process, allowing reading of the console and execution of commands.
-#[derive(Debug)]
 pub struct Puppet {
   child: Mutex<Child>,
-  child_stdout: Mutex<ChildStdout>,
-  child_stdin: Mutex<ChildStdin>
+  /// Notified when a task other than those waiting on the process needs `child`.
+  child_wanted: Notify,
+  /// The number of tasks other than those waiting on the process that are trying to lock `child`.
+  child_contenders: AtomicUsize,
+  id: Option<u32>,
+  /// Whether the process leads its own process group, which is then signalled as a whole.
+  process_group: bool,
+  child_stdout: Mutex<ChildOutput>,
+  child_stderr: Mutex<Option<ChildOutput>>,
+  child_stdin: Mutex<ChildInput>,
+  #[cfg(unix)]
+  pty_master: Option<std::fs::File>,
+  lines: broadcast::Sender<Arc<Line>>,
+  command_prefix: Option<String>,
+  dispatch: DispatchOptions,
+  dropped_lines: AtomicU64,
+  stop_sent: AtomicBool
 }
 
 impl Puppet {
@@ -87,32 +338,161 @@ impl Puppet {
   }
 
   /// Manually construct a puppet from a `Child`.
-  pub fn from_child(mut child: Child) -> Self {
+  /// The child's stdin and stdout must have been piped, and its stderr will be read as well if it was piped.
+  /// Unlike a puppet launched with `PuppetBuilder`, only the child itself is signalled, not its process group.
+  pub fn from_child(child: Child) -> Self {
+    Puppet::from_child_with(child, DispatchOptions::default())
+  }
+
+  /// Manually construct a puppet from a `Child`, with the given options for event handler dispatch.
+  pub fn from_child_with(mut child: Child, dispatch: DispatchOptions) -> Self {
     let child_stdout = child.stdout.take()
       .expect("no stdout captured");
     let child_stdin = child.stdin.take()
       .expect("no stdin captured");
+    let child_stderr = child.stderr.take()
+      .map(|child_stderr| Box::new(child_stderr) as ChildOutput);
+    Puppet::from_parts(child, Box::new(child_stdout), Box::new(child_stdin), child_stderr, None, dispatch)
+  }
+
+  fn from_parts(
+    child: Child,
+    child_stdout: ChildOutput,
+    child_stdin: ChildInput,
+    child_stderr: Option<ChildOutput>,
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pty_master: Option<std::fs::File>,
+    dispatch: DispatchOptions
+  ) -> Self {
+    let (lines, _) = broadcast::channel(LINE_CHANNEL_CAPACITY);
 
     Puppet {
+      id: child.id(),
+      process_group: false,
       child: Mutex::new(child),
+      child_wanted: Notify::new(),
+      child_contenders: AtomicUsize::new(0),
       child_stdout: Mutex::new(child_stdout),
-      child_stdin: Mutex::new(child_stdin)
+      child_stderr: Mutex::new(child_stderr),
+      child_stdin: Mutex::new(child_stdin),
+      #[cfg(unix)]
+      pty_master,
+      lines,
+      command_prefix: None,
+      dispatch,
+      dropped_lines: AtomicU64::new(0),
+      stop_sent: AtomicBool::new(false)
     }
   }
 
   /// Begin mirroring the process' stdin to the puppet's stdin, as well as mirroring
-  /// the puppet's stdout to an event handler and the process' stdout.
+  /// the puppet's stdout and stderr to an event handler and the process' stdout and stderr.
   /// The future returned by this function will resolve once the server has closed.
+  ///
+  /// The event handler runs behind a bounded queue, so that a slow handler does not hold up
+  /// the server's output, and a panicking handler is logged and skipped rather than unwinding.
+  /// Only console lines are subject to the queue's overflow policy, every other event is always delivered.
   pub async fn start(&self, event_handler: impl EventHandler) -> io::Result<()> {
-    tokio::try_join!(
-      self.start_dispatching_stdin(),
-      self.start_dispatching_stdout(&event_handler)
-    )?;
-    Ok(())
+    let queue = HandlerQueue::new(self.dispatch, &self.dropped_lines);
+    let (result, ()) = tokio::join!(
+      self.start_dispatching(&queue),
+      self.start_handling(&queue, &event_handler)
+    );
+    result
+  }
+
+  /// Get the OS-assigned process identifier of the server, if it was still running when
+  /// this puppet was constructed.
+  pub fn id(&self) -> Option<u32> {
+    self.id
+  }
+
+  /// The number of lines that were discarded because the event handler could not keep up.
+  pub fn dropped_lines(&self) -> u64 {
+    self.dropped_lines.load(Ordering::Relaxed)
+  }
+
+  /// Whether `stop` has been sent to the server's console, either with `Puppet::command` (as `Puppet::stop` does)
+  /// or by typing it into this process' stdin. This tells a deliberate exit apart from a crash even for servers
+  /// that do not announce that they are stopping.
+  pub fn stop_sent(&self) -> bool {
+    self.stop_sent.load(Ordering::Relaxed)
+  }
+
+  /// Runs both dispatch loops until the server's output closes, then waits for the process to
+  /// exit, queueing lifecycle events along the way. Closes the queue once finished.
+  async fn start_dispatching(&self, queue: &HandlerQueue<'_, Event>) -> io::Result<()> {
+    queue.push_reliable(Event::ProcessSpawned);
+
+    let dispatch_stdin = async {
+      self.start_dispatching_stdin(queue).await?;
+      // Keep forwarding the server's output even if there is no more input to send it
+      std::future::pending::<io::Result<()>>().await
+    };
+
+    let dispatch_output = async {
+      tokio::try_join!(
+        self.start_dispatching_stdout(queue),
+        self.start_dispatching_stderr(queue)
+      ).map(|_| ())
+    };
+
+    let result = tokio::select!{
+      result = dispatch_output => result,
+      result = dispatch_stdin => result,
+      result = self.start_propagating_window_size() => result
+    };
+
+    let result = match result {
+      Ok(()) => self.wait().await.map(Event::ProcessExited),
+      Err(err) => Err(err)
+    };
+
+    let result = match result {
+      Ok(event) => {
+        queue.push_reliable(event);
+        Ok(())
+      },
+      Err(err) => {
+        queue.push_reliable(Event::IoError(io::Error::new(err.kind(), err.to_string())));
+        Err(err)
+      }
+    };
+
+    queue.close();
+    result
+  }
+
+  /// Resizes the server's terminal whenever this process' terminal is resized, when in PTY mode.
+  /// The future returned by this function never resolves unless an error occurs.
+  async fn start_propagating_window_size(&self) -> io::Result<()> {
+    #[cfg(unix)]
+    if self.pty_master.is_some() {
+      use tokio::signal::unix::{signal, SignalKind};
+      let mut window_changes = signal(SignalKind::window_change())?;
+      while window_changes.recv().await.is_some() {
+        if let Some(size) = pty::terminal_size() {
+          self.resize(size)?;
+        };
+      };
+    };
+
+    std::future::pending().await
   }
 
-  /// Reads lines one at a time from stdin, sending each to the child stdin
-  async fn start_dispatching_stdin(&self) -> io::Result<()> {
+  /// Set the window size of the server's terminal.
+  /// Does nothing unless the server was launched with `PuppetBuilder::pty`.
+  #[cfg(unix)]
+  pub fn resize(&self, size: WindowSize) -> io::Result<()> {
+    match &self.pty_master {
+      Some(pty_master) => pty::set_window_size(pty_master, size),
+      None => Ok(())
+    }
+  }
+
+  /// Reads lines one at a time from stdin, sending each to the child stdin,
+  /// except for those starting with the command prefix, which are queued for the event handler
+  async fn start_dispatching_stdin(&self, queue: &HandlerQueue<'_, Event>) -> io::Result<()> {
     use std::io::ErrorKind;
     let mut process_stdin = BufReader::new(tokio::io::stdin());
     let mut buf = String::new();
@@ -123,11 +503,26 @@ impl Puppet {
         Err(e) => return Err(e)
       };
 
+      let wrapper_command = self.command_prefix.as_deref()
+        .and_then(|prefix| buf.trim_start().strip_prefix(prefix));
+      if let Some(wrapper_command) = wrapper_command {
+        queue.push_reliable(Event::WrapperCommand(wrapper_command.trim().to_owned()));
+        buf.clear();
+        continue;
+      };
+
+      if buf.trim() == "stop" {
+        self.stop_sent.store(true, Ordering::Relaxed);
+      };
+
       let mut child_stdin = self.child_stdin.lock().await;
-      match child_stdin.write_all(buf.as_bytes()).await {
+      match write_flush(&mut *child_stdin, buf.as_bytes()).await {
         Ok(()) => (),
         // If the pipe is broken, just ignore it and return `Ok`
-        Err(ref e) if e.kind() == ErrorKind::BrokenPipe => break,
+        Err(ref e) if e.kind() == ErrorKind::BrokenPipe => {
+          queue.push_reliable(Event::StdinClosed);
+          break;
+        },
         Err(e) => return Err(e)
       };
 
@@ -137,25 +532,46 @@ impl Puppet {
     Ok(())
   }
 
-  /// Reads lines one at a time from the child stdout, sending each to stdin.
+  /// Reads lines from the child stdout, mirroring each to stdout.
   /// NOTE: This function will lock the `child_stdout` mutex until it returns.
-  async fn start_dispatching_stdout(&self, event_handler: &impl EventHandler) -> io::Result<()> {
-    use std::io::ErrorKind;
-    let mut process_stdout = tokio::io::stdout();
+  async fn start_dispatching_stdout(&self, queue: &HandlerQueue<'_, Event>) -> io::Result<()> {
     let mut lock = self.child_stdout.lock().await;
-    let mut child_stdout = BufReader::new(&mut *lock);
+    self.start_dispatching_output(queue, &mut **lock, tokio::io::stdout(), OutputStream::Stdout).await
+  }
+
+  /// Reads lines from the child stderr (if it was captured), mirroring each to stderr.
+  /// NOTE: This function will lock the `child_stderr` mutex until it returns.
+  async fn start_dispatching_stderr(&self, queue: &HandlerQueue<'_, Event>) -> io::Result<()> {
+    let mut lock = self.child_stderr.lock().await;
+    match &mut *lock {
+      Some(child_stderr) => self.start_dispatching_output(queue, &mut **child_stderr, tokio::io::stderr(), OutputStream::Stderr).await,
+      None => Ok(())
+    }
+  }
+
+  /// Reads lines one at a time from one of the child's output streams,
+  /// mirroring each to `mirror` and sending it to subscribers and the handler queue.
+  async fn start_dispatching_output<R, W>(&self, queue: &HandlerQueue<'_, Event>, output: R, mut mirror: W, stream: OutputStream) -> io::Result<()>
+  where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    use std::io::ErrorKind;
+    let mut output = BufReader::new(output);
     let mut buf = String::new();
     loop {
-      match child_stdout.read_line(&mut buf).await {
+      match output.read_line(&mut buf).await {
         Ok(0) => break, Ok(_) => (),
         Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
         // If the pipe is broken, just ignore it and return `Ok`
         Err(ref e) if e.kind() == ErrorKind::BrokenPipe => break,
+        #[cfg(unix)]
+        Err(ref e) if pty::is_closed(e) => break,
         Err(e) => return Err(e)
       };
 
-      process_stdout.write_all(buf.as_bytes()).await?;
-      event_handler.console_line(self, buf.trim_end()).await;
+      mirror.write_all(buf.as_bytes()).await?;
+      let line = Arc::new(Line::new(buf.trim_end(), stream));
+      // An error here only means that nobody is currently subscribed
+      let _ = self.lines.send(line.clone());
+      queue.push(Event::ConsoleLine(line)).await;
 
       buf.clear();
     };
@@ -163,9 +579,28 @@ impl Puppet {
     Ok(())
   }
 
+  /// Takes events from the handler queue one at a time, passing each to the event handler.
+  async fn start_handling(&self, queue: &HandlerQueue<'_, Event>, event_handler: &impl EventHandler) {
+    while let Some(event) = queue.pop().await {
+      match event {
+        Event::ProcessSpawned => isolate(event_handler.process_spawned(self)).await,
+        Event::ConsoleLine(line) => isolate(event_handler.dispatch_line(self, &line)).await,
+        Event::ProcessExited(status) => isolate(event_handler.process_exited(self, status)).await,
+        Event::StdinClosed => isolate(event_handler.stdin_closed(self)).await,
+        Event::WrapperCommand(command) => isolate(event_handler.wrapper_command(self, &command)).await,
+        Event::IoError(err) => isolate(event_handler.io_error(self, &err)).await
+      };
+    };
+  }
+
   /// Send a command to the server's console.
   pub async fn command(&self, command: impl AsRef<str>) -> io::Result<()> {
-    let command = command.as_ref().trim().as_bytes();
+    let command = command.as_ref().trim();
+    if command == "stop" {
+      self.stop_sent.store(true, Ordering::Relaxed);
+    };
+
+    let command = command.as_bytes();
     let mut lock = self.child_stdin.lock().await;
     lock.write_all(command).await?;
     lock.write_u8(b'\n').await?;
@@ -173,27 +608,578 @@ impl Puppet {
     Ok(())
   }
 
+  /// Send a text component to the chat of the players matching `targets`, such as `@a`.
+  pub async fn tellraw(&self, targets: &str, text: &Text) -> io::Result<()> {
+    self.command(format!("tellraw {} {}", targets, text)).await
+  }
+
+  /// Show a text component on the screens of the players matching `targets`, such as `@a`.
+  pub async fn title(&self, targets: &str, slot: TitleSlot, text: &Text) -> io::Result<()> {
+    self.command(format!("title {} {} {}", targets, slot.as_str(), text)).await
+  }
+
+  /// Subscribe to the lines printed to the server's console, from both stdout and stderr.
+  /// Any number of subscriptions may exist at once, and each may be dropped at any time.
+  /// A subscriber that falls too far behind will miss lines rather than block the server.
+  ///
+  /// NOTE: Lines are only observed while `Puppet::start` is running.
+  pub fn subscribe(&self) -> Subscription {
+    Subscription::new(self.lines.subscribe())
+  }
+
+  /// Send a command to the server's console and collect the lines printed to stdout in response,
+  /// up to and including the first line for which `until` returns `true`.
+  /// Returns an error of kind `TimedOut` if no such line is seen before `timeout` elapses.
+  ///
+  /// NOTE: Lines are only observed while `Puppet::start` is running, and any unrelated
+  /// lines the server prints while the query is pending will be collected as well.
+  pub async fn query<F>(&self, command: impl AsRef<str>, until: F, timeout: Duration) -> io::Result<Vec<String>>
+  where F: FnMut(&str) -> bool {
+    let mut receiver = self.lines.subscribe();
+    self.command(command).await?;
+    match collect_lines(&mut receiver, until, None, Instant::now() + timeout).await? {
+      (lines, true) => Ok(lines),
+      (_, false) => Err(io::Error::new(io::ErrorKind::TimedOut, "query timed out"))
+    }
+  }
+
+  /// Send a command to the server's console and collect the lines printed to stdout in response,
+  /// until no new line has been printed for `quiet`, or until `timeout` elapses.
+  ///
+  /// NOTE: Lines are only observed while `Puppet::start` is running, and any unrelated
+  /// lines the server prints while the query is pending will be collected as well.
+  pub async fn query_quiet(&self, command: impl AsRef<str>, quiet: Duration, timeout: Duration) -> io::Result<Vec<String>> {
+    let mut receiver = self.lines.subscribe();
+    self.command(command).await?;
+    let (lines, _) = collect_lines(&mut receiver, |_| false, Some(quiet), Instant::now() + timeout).await?;
+    Ok(lines)
+  }
+
   /// Wait for the server to close.
+  /// Any number of tasks may wait at once, alongside `Puppet::stop` and the other methods acting on the process.
   pub async fn wait(&self) -> io::Result<ExitStatus> {
-    let mut lock = self.child.lock().await;
-    lock.wait().await
+    loop {
+      let mut lock = self.child.lock().await;
+      // Created before checking for contenders, so that it is woken by any that show up from here on
+      let wanted = self.child_wanted.notified();
+      if self.child_contenders.load(Ordering::SeqCst) == 0 {
+        tokio::select!{
+          result = lock.wait() => return result,
+          // Let go of the process whenever another task needs it, such as to signal it
+          () = wanted => ()
+        };
+      };
+
+      drop(lock);
+      tokio::task::yield_now().await;
+    };
   }
 
-  /// Force-kill the server.
+  /// Lock the process for anything other than waiting on it, making any task blocked in `Puppet::wait` let go of it.
+  async fn lock_child(&self) -> MutexGuard<'_, Child> {
+    struct Contender<'a>(&'a AtomicUsize);
+
+    impl Drop for Contender<'_> {
+      fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+      }
+    }
+
+    self.child_contenders.fetch_add(1, Ordering::SeqCst);
+    let _contender = Contender(&self.child_contenders);
+    self.child_wanted.notify_waiters();
+    self.child.lock().await
+  }
+
+  /// Force-kill the server, along with the rest of its process group if it was launched with `PuppetBuilder`.
   pub async fn kill(&self) -> io::Result<()> {
-    let mut lock = self.child.lock().await;
+    let mut lock = self.lock_child().await;
+    #[cfg(unix)]
+    if let (true, Some(id)) = (self.process_group, self.id) {
+      kill_process_group(id, libc::SIGKILL)?;
+    };
+
     lock.kill().await
   }
+
+  /// Ask the server's process to terminate (`SIGTERM`), which lets the JVM run its shutdown hooks.
+  /// If it was launched with `PuppetBuilder`, the rest of its process group is asked as well, so that a launcher
+  /// script does not leave the JVM behind. Does nothing if the process and its group have already exited.
+  /// This is only supported on Unix platforms, elsewhere an error of kind `Unsupported` is returned.
+  pub async fn terminate(&self) -> io::Result<()> {
+    self.signal(Signal::Terminate).await
+  }
+
+  /// Ask the JVM to print a thread dump of every thread to its stdout (`SIGQUIT`),
+  /// where it is dispatched like any other console output. Non-JVM servers may instead exit.
+  /// Does nothing if the process has already exited.
+  /// This is only supported on Unix platforms, elsewhere an error of kind `Unsupported` is returned.
+  pub async fn request_thread_dump(&self) -> io::Result<()> {
+    self.signal(Signal::Quit).await
+  }
+
+  async fn signal(&self, signal: Signal) -> io::Result<()> {
+    let mut lock = self.lock_child().await;
+    // Holding the lock ensures that the process is not reaped (and its pid reused) in the meantime,
+    // while its process group keeps its id for as long as anything in it is still running
+    let exited = lock.try_wait()?.is_some();
+    match self.id {
+      #[cfg(unix)]
+      Some(id) => {
+        let signal = match signal {
+          Signal::Terminate => libc::SIGTERM,
+          Signal::Quit => libc::SIGQUIT
+        };
+
+        match (self.process_group, exited) {
+          (true, _) => kill_process_group(id, signal),
+          (false, true) => Ok(()),
+          (false, false) => match unsafe { libc::kill(id as libc::pid_t, signal) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error())
+          }
+        }
+      },
+      #[cfg(not(unix))]
+      Some(_) => {
+        let _ = (signal, exited);
+        Err(io::Error::new(io::ErrorKind::Unsupported, "signalling a process requires unix signals"))
+      },
+      None => Ok(())
+    }
+  }
+
+  /// Stop the server gracefully, escalating when it does not exit in time:
+  /// first `save-all flush` (optionally) and `stop` are sent to the console, then the process is
+  /// sent `SIGTERM`, and finally it is force-killed. Returns the stage that ended the process.
+  /// Unless `options.graceful` is set, the process is force-killed straight away.
+  ///
+  /// This may be called while `Puppet::start` is running or other tasks are blocked in `Puppet::wait`.
+  pub async fn stop(&self, options: StopOptions) -> io::Result<StopOutcome> {
+    if let Some(status) = self.lock_child().await.try_wait()? {
+      return Ok(StopOutcome { stage: StopStage::AlreadyExited, status });
+    };
+
+    if !options.graceful {
+      self.kill().await?;
+      let status = self.wait().await?;
+      return Ok(StopOutcome { stage: StopStage::Killed, status });
+    };
+
+    let commands = async {
+      if options.save_first {
+        self.command("save-all flush").await?;
+      };
+
+      self.command("stop").await
+    };
+
+    match commands.await {
+      Ok(()) => (),
+      // The server can no longer receive commands, so skip straight to terminating it
+      Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+      Err(e) => return Err(e)
+    };
+
+    if let Some(status) = self.wait_timeout(options.stop_timeout).await? {
+      return Ok(StopOutcome { stage: StopStage::Stopped, status });
+    };
+
+    match self.terminate().await {
+      Ok(()) => if let Some(status) = self.wait_timeout(options.term_timeout).await? {
+        return Ok(StopOutcome { stage: StopStage::Terminated, status });
+      },
+      Err(ref e) if e.kind() == io::ErrorKind::Unsupported => (),
+      Err(e) => return Err(e)
+    };
+
+    self.kill().await?;
+    let status = self.wait().await?;
+    Ok(StopOutcome { stage: StopStage::Killed, status })
+  }
+
+  /// Wait for the server to close, returning `None` if it has not closed within `timeout`.
+  async fn wait_timeout(&self, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    match tokio::time::timeout(timeout, self.wait()).await {
+      Ok(result) => result.map(Some),
+      Err(_) => Ok(None)
+    }
+  }
+}
+
+/// Sends `signal` to every process in the process group led by `id`, which is not an error if they have all exited.
+#[cfg(unix)]
+fn kill_process_group(id: u32, signal: libc::c_int) -> io::Result<()> {
+  match unsafe { libc::kill(-(id as libc::pid_t), signal) } {
+    0 => Ok(()),
+    _ => match io::Error::last_os_error() {
+      err if err.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+      err => Err(err)
+    }
+  }
+}
+
+/// Options for stopping a server with `Puppet::stop`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StopOptions {
+  /// Whether to run `save-all flush` before sending `stop`.
+  pub save_first: bool,
+  /// How long to wait for the server to exit after sending `stop`.
+  pub stop_timeout: Duration,
+  /// How long to wait for the process to exit after sending `SIGTERM`, before force-killing it.
+  pub term_timeout: Duration,
+  /// Whether to send `stop` and `SIGTERM` at all, rather than force-killing the process straight away.
+  /// Both make the server save its worlds on the way out.
+  pub graceful: bool
+}
+
+impl Default for StopOptions {
+  fn default() -> Self {
+    StopOptions {
+      save_first: true,
+      stop_timeout: Duration::from_secs(60),
+      term_timeout: Duration::from_secs(30),
+      graceful: true
+    }
+  }
+}
+
+/// The stage of `Puppet::stop` that ended the server's process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopStage {
+  /// The process had already exited before stopping began.
+  AlreadyExited,
+  /// The process exited after the `stop` command.
+  Stopped,
+  /// The process exited after being sent `SIGTERM`.
+  Terminated,
+  /// The process had to be force-killed.
+  Killed
+}
+
+impl fmt::Display for StopStage {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      StopStage::AlreadyExited => "already exited",
+      StopStage::Stopped => "stopped",
+      StopStage::Terminated => "terminated",
+      StopStage::Killed => "killed"
+    })
+  }
+}
+
+/// The result of stopping a server with `Puppet::stop`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StopOutcome {
+  /// The stage that ended the process.
+  pub stage: StopStage,
+  /// The process' exit status.
+  pub status: ExitStatus
+}
+
+/// Which of the server's output streams a line was printed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+  Stdout,
+  Stderr
+}
+
+/// A single line printed to the server's console.
+#[derive(Debug, Clone)]
+pub struct Line {
+  /// The text of the line, without its line ending.
+  pub text: String,
+  /// The stream the line was printed to.
+  pub stream: OutputStream,
+  #[cfg(feature = "parsing")]
+  parsed: OnceLock<Option<ConsoleLine>>
+}
+
+impl Line {
+  pub(crate) fn new(text: &str, stream: OutputStream) -> Self {
+    Line {
+      text: text.to_owned(),
+      stream,
+      #[cfg(feature = "parsing")]
+      parsed: OnceLock::new()
+    }
+  }
+
+  /// The line parsed as a `ConsoleLine`, if it is recognized.
+  /// The line is only parsed the first time this is called, and the result is shared with every other caller.
+  #[cfg(feature = "parsing")]
+  pub fn parsed(&self) -> Option<&ConsoleLine> {
+    self.parsed.get_or_init(|| ConsoleLine::parse_from(&self.text)).as_ref()
+  }
+}
+
+/// A stream of console lines, obtained from `Puppet::subscribe`.
+#[derive(Debug)]
+pub struct Subscription {
+  inner: BroadcastStream<Arc<Line>>,
+  missed: u64
+}
+
+impl Subscription {
+  fn new(receiver: broadcast::Receiver<Arc<Line>>) -> Self {
+    Subscription { inner: BroadcastStream::new(receiver), missed: 0 }
+  }
+
+  /// The number of lines this subscription has missed by falling behind.
+  pub fn missed(&self) -> u64 {
+    self.missed
+  }
+}
+
+impl Stream for Subscription {
+  type Item = Arc<Line>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    loop {
+      match Pin::new(&mut self.inner).poll_next(cx) {
+        Poll::Ready(Some(Err(BroadcastStreamRecvError::Lagged(n)))) => self.missed += n,
+        Poll::Ready(Some(Ok(line))) => return Poll::Ready(Some(line)),
+        Poll::Ready(None) => return Poll::Ready(None),
+        Poll::Pending => return Poll::Pending
+      };
+    }
+  }
+}
+
+impl fmt::Debug for Puppet {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Puppet")
+      .field("id", &self.id)
+      .field("dispatch", &self.dispatch)
+      .field("dropped_lines", &self.dropped_lines)
+      .finish_non_exhaustive()
+  }
+}
+
+async fn write_flush<W>(writer: &mut W, buf: &[u8]) -> io::Result<()>
+where W: AsyncWrite + Unpin + ?Sized {
+  writer.write_all(buf).await?;
+  writer.flush().await
+}
+
+/// Receives lines until `until` matches one (returning `true`), or until stdout
+/// has been quiet for `quiet` or `deadline` has passed (returning `false`).
+async fn collect_lines<F>(
+  receiver: &mut broadcast::Receiver<Arc<Line>>,
+  mut until: F,
+  quiet: Option<Duration>,
+  deadline: Instant
+) -> io::Result<(Vec<String>, bool)>
+where F: FnMut(&str) -> bool {
+  let quiet_until = || match quiet {
+    Some(quiet) => deadline.min(Instant::now() + quiet),
+    None => deadline
+  };
+
+  let mut lines = Vec::new();
+  let mut wait_until = quiet_until();
+  loop {
+    match tokio::time::timeout_at(wait_until, receiver.recv()).await {
+      // Only lines printed to stdout count as a response, so stderr does not reset the quiet timer either
+      Ok(Ok(line)) if line.stream == OutputStream::Stderr => continue,
+      Ok(Ok(line)) => {
+        let done = until(&line.text);
+        lines.push(line.text.clone());
+        if done { return Ok((lines, true)) };
+        wait_until = quiet_until();
+      },
+      // The query fell behind, some lines were missed but the rest are still useful
+      Ok(Err(RecvError::Lagged(_))) => continue,
+      Ok(Err(RecvError::Closed)) => return Err(io::ErrorKind::BrokenPipe.into()),
+      Err(_) => return Ok((lines, false))
+    };
+  }
+}
+
+/// Signals that can be sent to the server's process.
+#[derive(Debug, Clone, Copy)]
+enum Signal {
+  Terminate,
+  Quit
+}
+
+/// Events queued up for an event handler by `Puppet::start`.
+#[derive(Debug)]
+enum Event {
+  ProcessSpawned,
+  ConsoleLine(Arc<Line>),
+  ProcessExited(ExitStatus),
+  StdinClosed,
+  WrapperCommand(String),
+  IoError(io::Error)
 }
 
 /// The core trait for handling events dispatched by a puppet.
 #[async_trait]
 pub trait EventHandler: Send + Sync {
+  /// Dispatched once when `Puppet::start` is called, as the server process has already been spawned.
+  async fn process_spawned(&self, _puppet: &Puppet) {}
+
+  /// Dispatched for every line the server prints to either stdout or stderr. By default, this passes the line
+  /// on to `console_line` or `stderr_line`, and then to `dispatch_parsed_line`.
+  /// Combinators override this to decide about a line along with every callback derived from it.
+  async fn dispatch_line(&self, puppet: &Puppet, line: &Line) {
+    match line.stream {
+      OutputStream::Stdout => isolate(self.console_line(puppet, &line.text)).await,
+      OutputStream::Stderr => isolate(self.stderr_line(puppet, &line.text)).await
+    };
+
+    #[cfg(feature = "parsing")]
+    isolate(self.dispatch_parsed_line(puppet, line)).await;
+  }
+
+  /// Dispatched by `dispatch_line` for every line. By default, this passes the line on to `console_event` if it
+  /// is recognized as a `ConsoleLine`, followed by `server_ready` or `server_stopping` if it is one of those.
+  #[cfg(feature = "parsing")]
+  async fn dispatch_parsed_line(&self, puppet: &Puppet, line: &Line) {
+    if let Some(parsed) = line.parsed() {
+      isolate(self.console_event(puppet, parsed)).await;
+      match *parsed {
+        ConsoleLine::DoneLoading { time } => isolate(self.server_ready(puppet, time)).await,
+        ConsoleLine::StoppingServer => isolate(self.server_stopping(puppet)).await,
+        _ => ()
+      };
+    };
+  }
+
   /// Dispatched when the minecraft server spits out a line in the console.
   async fn console_line(&self, _puppet: &Puppet, _line: &str) {}
+
+  /// Dispatched when the minecraft server prints a line to stderr, such as JVM errors and crash reports.
+  async fn stderr_line(&self, _puppet: &Puppet, _line: &str) {}
+
+  /// Dispatched after `console_line` or `stderr_line` when the line is recognized as a `ConsoleLine`.
+  #[cfg(feature = "parsing")]
+  async fn console_event(&self, _puppet: &Puppet, _line: &ConsoleLine) {}
+
+  /// Dispatched when the server has finished starting up (`ConsoleLine::DoneLoading`),
+  /// with the startup time in seconds reported by the server.
+  #[cfg(feature = "parsing")]
+  async fn server_ready(&self, _puppet: &Puppet, _time: f64) {}
+
+  /// Dispatched when the server announces that it is shutting down (`ConsoleLine::StoppingServer`).
+  #[cfg(feature = "parsing")]
+  async fn server_stopping(&self, _puppet: &Puppet) {}
+
+  /// Dispatched when the server process has exited, after all of its output has been handled.
+  async fn process_exited(&self, _puppet: &Puppet, _status: ExitStatus) {}
+
+  /// Dispatched when the server's stdin has been closed and commands can no longer be sent to it.
+  async fn stdin_closed(&self, _puppet: &Puppet) {}
+
+  /// Dispatched when a line starting with the prefix set by `PuppetBuilder::command_prefix` is typed
+  /// into this process' stdin, with the prefix removed. The line is not sent to the server.
+  async fn wrapper_command(&self, _puppet: &Puppet, _command: &str) {}
+
+  /// Dispatched when reading from or writing to the server fails, just before `Puppet::start` returns the error.
+  async fn io_error(&self, _puppet: &Puppet, _error: &io::Error) {}
 }
 
 pub struct NoHandler;
 
 #[async_trait]
 impl EventHandler for NoHandler {}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::*;
+  use tokio::io::AsyncReadExt;
+
+  fn spawn_cat() -> Puppet {
+    let child = Command::new("cat")
+      .stdin(Stdio::piped()).stdout(Stdio::piped())
+      .kill_on_drop(true)
+      .spawn().unwrap();
+    Puppet::from_child(child)
+  }
+
+  #[tokio::test]
+  async fn stderr_does_not_reset_quiet_timer() {
+    let (sender, mut receiver) = broadcast::channel(LINE_CHANNEL_CAPACITY);
+    sender.send(Arc::new(Line::new("response", OutputStream::Stdout))).unwrap();
+    let stderr = async {
+      loop {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let _ = sender.send(Arc::new(Line::new("noise", OutputStream::Stderr)));
+      }
+    };
+
+    let started = Instant::now();
+    let collect = collect_lines(&mut receiver, |_| false, Some(Duration::from_millis(200)), started + Duration::from_secs(5));
+    let (lines, matched) = tokio::select!{
+      result = collect => result.unwrap(),
+      () = stderr => unreachable!()
+    };
+    assert_eq!(lines, ["response"]);
+    assert!(!matched);
+    assert!(started.elapsed() < Duration::from_secs(1));
+  }
+
+  #[tokio::test]
+  async fn kill_while_waiting() {
+    let puppet = spawn_cat();
+    let kill = async {
+      tokio::time::sleep(Duration::from_millis(100)).await;
+      puppet.kill().await
+    };
+
+    let result = tokio::time::timeout(Duration::from_secs(5), async {
+      tokio::join!(puppet.wait(), puppet.wait(), kill)
+    }).await;
+    let (first, second, kill) = result.expect("waiting blocked the kill");
+    kill.unwrap();
+    assert!(!first.unwrap().success());
+    assert!(!second.unwrap().success());
+  }
+
+  #[tokio::test]
+  async fn stop_while_waiting() {
+    let puppet = spawn_cat();
+    let options = StopOptions { stop_timeout: Duration::from_millis(100), ..StopOptions::default() };
+    let result = tokio::time::timeout(Duration::from_secs(5), async {
+      tokio::join!(puppet.wait(), puppet.stop(options))
+    }).await;
+    let (status, outcome) = result.expect("waiting blocked the stop");
+    let outcome = outcome.unwrap();
+    assert_eq!(outcome.stage, StopStage::Terminated);
+    assert_eq!(status.unwrap(), outcome.status);
+  }
+
+  #[tokio::test]
+  async fn stop_reaches_processes_started_by_a_launcher() {
+    // `sleep` holds on to the launcher's output, so the output only closes once it has been stopped too
+    let puppet = Puppet::builder().command("sh", ["-c", "sleep 1000 & wait"]).finish().unwrap();
+    let options = StopOptions { save_first: false, stop_timeout: Duration::from_millis(100), ..StopOptions::default() };
+    let output_closed = async {
+      let mut output = Vec::new();
+      puppet.child_stdout.lock().await.read_to_end(&mut output).await
+    };
+
+    let result = tokio::time::timeout(Duration::from_secs(5), async {
+      tokio::join!(output_closed, puppet.stop(options))
+    }).await;
+    let (output, outcome) = result.expect("a process started by the launcher was left running");
+    output.unwrap();
+    assert_eq!(outcome.unwrap().stage, StopStage::Terminated);
+  }
+
+  #[tokio::test]
+  async fn stop_without_grace() {
+    let puppet = spawn_cat();
+    let options = StopOptions { graceful: false, ..StopOptions::default() };
+    let outcome = tokio::time::timeout(Duration::from_secs(5), puppet.stop(options)).await
+      .expect("the process was not killed straight away")
+      .unwrap();
+    assert_eq!(outcome.stage, StopStage::Killed);
+
+    // `cat` echoes its input, so any command sent to it would show up in its output
+    let mut output = String::new();
+    puppet.child_stdout.lock().await.read_to_string(&mut output).await.unwrap();
+    assert_eq!(output, "");
+  }
+}
@@ -0,0 +1,278 @@
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+/// A JSON text component, as accepted by `tellraw`, `title` and `bossbar`.
+/// Built up from plain text with optional formatting and further components appended to it,
+/// which inherit its formatting unless they override it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Text {
+  text: String,
+  color: Option<Color>,
+  bold: Option<bool>,
+  italic: Option<bool>,
+  underlined: Option<bool>,
+  strikethrough: Option<bool>,
+  obfuscated: Option<bool>,
+  extra: Vec<Text>
+}
+
+impl Text {
+  /// A component containing plain text.
+  pub fn new(text: impl Into<String>) -> Self {
+    Text { text: text.into(), ..Text::default() }
+  }
+
+  /// Set the color of this component's text and of any appended components that don't set their own.
+  pub fn color(mut self, color: Color) -> Self {
+    self.color = Some(color);
+    self
+  }
+
+  /// Set whether this component's text is bold, `false` overriding an inherited bold.
+  pub fn bold(mut self, bold: bool) -> Self {
+    self.bold = Some(bold);
+    self
+  }
+
+  /// Set whether this component's text is italic, `false` overriding an inherited italic.
+  pub fn italic(mut self, italic: bool) -> Self {
+    self.italic = Some(italic);
+    self
+  }
+
+  /// Set whether this component's text is underlined, `false` overriding an inherited underline.
+  pub fn underlined(mut self, underlined: bool) -> Self {
+    self.underlined = Some(underlined);
+    self
+  }
+
+  /// Set whether this component's text is struck through, `false` overriding an inherited strikethrough.
+  pub fn strikethrough(mut self, strikethrough: bool) -> Self {
+    self.strikethrough = Some(strikethrough);
+    self
+  }
+
+  /// Set whether this component's text is obfuscated, cycling through random characters in game.
+  pub fn obfuscated(mut self, obfuscated: bool) -> Self {
+    self.obfuscated = Some(obfuscated);
+    self
+  }
+
+  /// Append a component after this one's text.
+  pub fn append(mut self, text: impl Into<Text>) -> Self {
+    self.extra.push(text.into());
+    self
+  }
+
+  /// This component as JSON, such as `{"text":"Hello","color":"red"}`, with its text escaped
+  /// so that it can be passed straight to a command.
+  pub fn to_json(&self) -> String {
+    self.to_string()
+  }
+}
+
+impl From<&str> for Text {
+  fn from(text: &str) -> Self {
+    Text::new(text)
+  }
+}
+
+impl From<String> for Text {
+  fn from(text: String) -> Self {
+    Text::new(text)
+  }
+}
+
+/// Formats the component as JSON.
+impl fmt::Display for Text {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("{\"text\":")?;
+    write_json_string(f, &self.text)?;
+    if let Some(color) = self.color {
+      write!(f, ",\"color\":\"{}\"", color)?;
+    };
+
+    let flags = [
+      ("bold", self.bold),
+      ("italic", self.italic),
+      ("underlined", self.underlined),
+      ("strikethrough", self.strikethrough),
+      ("obfuscated", self.obfuscated)
+    ];
+    for (name, flag) in flags {
+      if let Some(flag) = flag {
+        write!(f, ",\"{}\":{}", name, flag)?;
+      };
+    };
+
+    if !self.extra.is_empty() {
+      f.write_str(",\"extra\":[")?;
+      for (i, extra) in self.extra.iter().enumerate() {
+        if i > 0 { f.write_char(',')? };
+        write!(f, "{}", extra)?;
+      };
+      f.write_char(']')?;
+    };
+
+    f.write_char('}')
+  }
+}
+
+/// Writes `s` as a quoted JSON string, escaping quotes, backslashes and control characters.
+fn write_json_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+  f.write_char('"')?;
+  for c in s.chars() {
+    match c {
+      '"' => f.write_str("\\\"")?,
+      '\\' => f.write_str("\\\\")?,
+      '\n' => f.write_str("\\n")?,
+      '\r' => f.write_str("\\r")?,
+      '\t' => f.write_str("\\t")?,
+      c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+      c => f.write_char(c)?
+    };
+  };
+
+  f.write_char('"')
+}
+
+macro_rules! colors {
+  ($($variant:ident => $name:literal),* $(,)?) => {
+    /// The color of a text component, either one of the 16 named chat colors or an RGB color.
+    /// RGB colors require Minecraft 1.16 or newer.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Color {
+      $($variant,)*
+      Rgb(u8, u8, u8)
+    }
+
+    impl FromStr for Color {
+      type Err = UnknownColor;
+
+      /// Parses a named color such as `dark_red`, or an RGB color such as `#ff8000`.
+      fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || UnknownColor(s.to_owned());
+        match s.to_ascii_lowercase().as_str() {
+          $($name => Ok(Color::$variant),)*
+          hex => {
+            let hex = hex.strip_prefix('#').filter(|hex| hex.len() == 6).ok_or_else(error)?;
+            let rgb = u32::from_str_radix(hex, 16).map_err(|_| error())?;
+            Ok(Color::Rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+          }
+        }
+      }
+    }
+
+    impl fmt::Display for Color {
+      fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+          $(Color::$variant => f.write_str($name),)*
+          Color::Rgb(r, g, b) => write!(f, "#{:02x}{:02x}{:02x}", r, g, b)
+        }
+      }
+    }
+  };
+}
+
+colors! {
+  Black => "black",
+  DarkBlue => "dark_blue",
+  DarkGreen => "dark_green",
+  DarkAqua => "dark_aqua",
+  DarkRed => "dark_red",
+  DarkPurple => "dark_purple",
+  Gold => "gold",
+  Gray => "gray",
+  DarkGray => "dark_gray",
+  Blue => "blue",
+  Green => "green",
+  Aqua => "aqua",
+  Red => "red",
+  LightPurple => "light_purple",
+  Yellow => "yellow",
+  White => "white"
+}
+
+/// The error returned when parsing a `Color` from an unrecognized name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownColor(pub String);
+
+impl fmt::Display for UnknownColor {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "unknown color `{}`, expected a chat color such as `dark_red`, or an RGB color such as `#ff8000`", self.0)
+  }
+}
+
+impl std::error::Error for UnknownColor {}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Color {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Color {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// Where `Puppet::title` shows its text on the players' screens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TitleSlot {
+  /// Large text in the middle of the screen.
+  Title,
+  /// Smaller text below the title, only shown along with a title.
+  Subtitle,
+  /// Text just above the hotbar.
+  Actionbar
+}
+
+impl TitleSlot {
+  pub(crate) fn as_str(self) -> &'static str {
+    match self {
+      TitleSlot::Title => "title",
+      TitleSlot::Subtitle => "subtitle",
+      TitleSlot::Actionbar => "actionbar"
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn plain_text() {
+    assert_eq!(Text::new("Hello").to_json(), r#"{"text":"Hello"}"#);
+    assert_eq!(Text::new("").to_json(), r#"{"text":""}"#);
+  }
+
+  #[test]
+  fn escapes_quotes_and_backslashes() {
+    assert_eq!(Text::new(r#"say "hi""#).to_json(), r#"{"text":"say \"hi\""}"#);
+    assert_eq!(Text::new(r"C:\server").to_json(), r#"{"text":"C:\\server"}"#);
+  }
+
+  #[test]
+  fn escapes_control_characters() {
+    assert_eq!(Text::new("a\nb\r\tc").to_json(), r#"{"text":"a\nb\r\tc"}"#);
+    assert_eq!(Text::new("\u{0}\u{1b}\u{7f}").to_json(), r#"{"text":"\u0000\u001b\u007f"}"#);
+  }
+
+  #[test]
+  fn keeps_unicode() {
+    assert_eq!(Text::new("§ ünïcödé ✓").to_json(), r#"{"text":"§ ünïcödé ✓"}"#);
+  }
+
+  #[test]
+  fn formatting_and_extra() {
+    let text = Text::new("Restarting in ").color(Color::Gold).bold(true)
+      .append(Text::new("5 \"minutes\"").color(Color::Rgb(255, 128, 0)).bold(false));
+    assert_eq!(text.to_json(),
+      r##"{"text":"Restarting in ","color":"gold","bold":true,"extra":[{"text":"5 \"minutes\"","color":"#ff8000","bold":false}]}"##);
+  }
+}
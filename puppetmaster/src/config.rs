@@ -1,19 +1,65 @@
-use chrono::prelude::*;
+use chrono::NaiveTime;
+use puppet::{MemorySize, StopOptions};
 
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::{PathBuf, Path};
 
+use crate::crash::CrashConfig;
+use crate::errors::ErrorsConfig;
+use crate::overload::OverloadConfig;
+use crate::resources::ResourcesConfig;
+use crate::schedule::{Schedule, Timezone};
+use crate::vote::VoteConfig;
+use crate::warning::{self, Warning};
+use crate::watchdog::WatchdogConfig;
 use crate::Error;
 
 
 
 #[derive(Debug, Serialize, Deserialize)]
-#[serde(default, rename_all = "kebab-case")]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Config {
+  pub java: Option<PathBuf>,
   pub jar_path: PathBuf,
-  pub max_memory: String,
-  pub min_memory: String,
-  pub restart_time: NaiveTime
+  pub working_dir: Option<PathBuf>,
+  pub pty: bool,
+  pub max_memory: MemorySize,
+  pub min_memory: MemorySize,
+  pub jvm_preset: Option<String>,
+  pub gc_logging: bool,
+  pub jvm_args: Vec<String>,
+  pub server_args: Vec<String>,
+  /// Lines typed into the console starting with this prefix are commands for puppetmaster, such as `!pm restart in 10m`.
+  pub command_prefix: Option<String>,
+  pub env: BTreeMap<String, String>,
+  pub launch: Option<LaunchConfig>,
+  pub schedule: Schedule,
+  pub crash: CrashConfig,
+  pub watchdog: WatchdogConfig,
+  pub vote: VoteConfig,
+  pub overload: OverloadConfig,
+  pub resources: ResourcesConfig,
+  pub errors: ErrorsConfig,
+  /// Given as `[[warnings]]`, which must come last.
+  pub warnings: Vec<Warning>,
+  /// Replaced by `schedule.times`, and moved there when the config is loaded.
+  #[serde(skip_serializing)]
+  pub restart_time: Option<NaiveTime>,
+  /// Replaced by `schedule.timezone`, and moved there when the config is loaded.
+  #[serde(skip_serializing)]
+  pub timezone: Option<Timezone>
+}
+
+/// Launches an arbitrary program instead of a Java server, such as a Bedrock Dedicated Server
+/// or a launcher script, given as `launch = { program = "...", args = [...] }`.
+/// Such a program is only sent `stop` when it is stopped, since it may not understand `save-all flush`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct LaunchConfig {
+  pub program: PathBuf,
+  #[serde(default)]
+  pub args: Vec<String>
 }
 
 impl Config {
@@ -22,7 +68,11 @@ impl Config {
     let path = path.as_ref().to_owned();
     asyncify(move || {
       Ok(match fs::read(&path) {
-        Ok(data) => toml::from_slice::<Config>(&data)?,
+        Ok(data) => {
+          let config = toml::from_slice::<Config>(&data)?.migrate();
+          config.vote.validate()?;
+          config
+        },
         Err(err) if err.kind() == ErrorKind::NotFound => {
           let config = Config::default();
           let data = toml::to_vec(&config)?;
@@ -34,16 +84,42 @@ impl Config {
     }).await
   }
 
-  pub fn next_restart<Tz: TimeZone>(&self, now: DateTime<Tz>) -> DateTime<Tz> {
-    let time = now.date()
-      .and_time(self.restart_time)
-      .expect("error getting restart time");
-    if time <= now {
-      now.date().succ()
-        .and_time(self.restart_time)
-        .expect("error getting restart time")
-    } else {
-      time
+  /// Moves settings from older versions of the config to where they are now, warning about each of them.
+  fn migrate(mut self) -> Self {
+    if let Some(restart_time) = self.restart_time.take() {
+      println!("[Puppetmaster] Config Warning: `restart-time` is deprecated, use `times` under `[schedule]` instead");
+      self.schedule.times = vec![restart_time];
+    };
+
+    if let Some(timezone) = self.timezone.take() {
+      println!("[Puppetmaster] Config Warning: `timezone` is deprecated, use `timezone` under `[schedule]` instead");
+      self.schedule.timezone = timezone;
+    };
+
+    self
+  }
+
+  /// How the server is stopped for restarts and errors.
+  pub fn stop_options(&self) -> StopOptions {
+    StopOptions { save_first: self.launch.is_none(), ..StopOptions::default() }
+  }
+
+  /// Checks that the configured memory sizes can be resolved, that `min-memory` does not
+  /// exceed `max-memory`, and that this system has at least `max-memory` of memory.
+  pub fn validate_memory(&self) -> Result<(), Error> {
+    let max_memory = self.max_memory.to_bytes()
+      .ok_or(Error::UnknownSystemMemory(self.max_memory))?;
+    let min_memory = self.min_memory.to_bytes()
+      .ok_or(Error::UnknownSystemMemory(self.min_memory))?;
+    if min_memory > max_memory {
+      return Err(Error::MinMemoryExceedsMax(self.min_memory, self.max_memory));
+    };
+
+    match puppet::system_memory() {
+      Some(system_memory) if max_memory > system_memory => {
+        Err(Error::InsufficientMemory(self.max_memory, system_memory as f64 / (1024 * 1024 * 1024) as f64))
+      },
+      _ => Ok(())
     }
   }
 }
@@ -51,10 +127,29 @@ impl Config {
 impl Default for Config {
   fn default() -> Self {
     Config {
+      java: None,
       jar_path: "server.jar".into(),
-      max_memory: "2g".into(),
-      min_memory: "2g".into(),
-      restart_time: NaiveTime::from_hms(22, 0, 0)
+      working_dir: None,
+      pty: false,
+      max_memory: MemorySize::gigabytes(2),
+      min_memory: MemorySize::gigabytes(2),
+      jvm_preset: None,
+      gc_logging: false,
+      jvm_args: Vec::new(),
+      server_args: Vec::new(),
+      command_prefix: Some("!pm".to_owned()),
+      env: BTreeMap::new(),
+      launch: None,
+      schedule: Schedule::default(),
+      crash: CrashConfig::default(),
+      watchdog: WatchdogConfig::default(),
+      vote: VoteConfig::default(),
+      overload: OverloadConfig::default(),
+      resources: ResourcesConfig::default(),
+      errors: ErrorsConfig::default(),
+      warnings: warning::default_warnings(),
+      restart_time: None,
+      timezone: None
     }
   }
 }
@@ -62,7 +157,7 @@ impl Default for Config {
 pub(crate) async fn asyncify<F, T>(f: F) -> Result<T, Error>
 where F: FnOnce() -> Result<T, Error> + Send + 'static, T: Send + 'static {
   match tokio::task::spawn_blocking(f).await {
-    Ok(res) => res.map_err(From::from),
+    Ok(res) => res,
     Err(_) => Err(Error::BackgroundTaskFailed)
   }
 }
@@ -0,0 +1,131 @@
+use tokio::time::Instant;
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// How to react when the server exits without being asked to.
+/// An exit counts as a crash if the exit status was unsuccessful, or if the server was never sent `stop`
+/// and never announced that it was stopping.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CrashConfig {
+  /// Whether to restart the server after it crashes.
+  pub auto_restart: bool,
+  /// How long to wait before restarting after the first crash, doubling for each further crash in the window.
+  #[serde(with = "humantime_serde")]
+  pub initial_backoff: Duration,
+  /// The longest to wait before restarting after a crash.
+  #[serde(with = "humantime_serde")]
+  pub max_backoff: Duration,
+  /// How many crashes within `crash-window` are tolerated before giving up.
+  pub max_crashes: usize,
+  /// How far back crashes are counted, such as `"30m"`.
+  #[serde(with = "humantime_serde")]
+  pub crash_window: Duration
+}
+
+impl Default for CrashConfig {
+  fn default() -> Self {
+    CrashConfig {
+      auto_restart: true,
+      initial_backoff: Duration::from_secs(5),
+      max_backoff: Duration::from_secs(5 * 60),
+      max_crashes: 5,
+      crash_window: Duration::from_secs(30 * 60)
+    }
+  }
+}
+
+/// Keeps track of recent crashes, to space out restarts and detect crash loops.
+#[derive(Debug)]
+pub struct CrashTracker {
+  initial_backoff: Duration,
+  max_backoff: Duration,
+  max_crashes: usize,
+  window: Duration,
+  crashes: VecDeque<Instant>
+}
+
+impl CrashTracker {
+  pub fn new(config: &CrashConfig) -> Self {
+    CrashTracker {
+      initial_backoff: config.initial_backoff,
+      max_backoff: config.max_backoff,
+      max_crashes: config.max_crashes,
+      window: config.crash_window,
+      crashes: VecDeque::new()
+    }
+  }
+
+  /// Records a crash, returning how long to wait before restarting the server,
+  /// or `None` if there have been too many crashes within the crash window to keep trying.
+  pub fn record(&mut self, now: Instant) -> Option<Duration> {
+    while let Some(&crash) = self.crashes.front() {
+      if now.duration_since(crash) < self.window { break };
+      self.crashes.pop_front();
+    };
+
+    self.crashes.push_back(now);
+    if self.crashes.len() >= self.max_crashes.max(1) {
+      return None;
+    };
+
+    // Double the backoff for each crash still within the window
+    let exponent = (self.crashes.len() - 1).min(31) as u32;
+    let backoff = self.initial_backoff.saturating_mul(2u32.pow(exponent));
+    Some(backoff.min(self.max_backoff))
+  }
+
+  /// The number of crashes within the crash window, as of the last recorded crash.
+  pub fn recent_crashes(&self) -> usize {
+    self.crashes.len()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tracker(max_crashes: usize) -> CrashTracker {
+    CrashTracker::new(&CrashConfig {
+      auto_restart: true,
+      initial_backoff: Duration::from_secs(5),
+      max_backoff: Duration::from_secs(30),
+      max_crashes,
+      crash_window: Duration::from_secs(30 * 60)
+    })
+  }
+
+  #[test]
+  fn backoff_doubles_up_to_max() {
+    let mut tracker = tracker(10);
+    let start = Instant::now();
+    let backoffs = (0..6)
+      .map(|n| tracker.record(start + Duration::from_secs(n * 60)).unwrap().as_secs())
+      .collect::<Vec<u64>>();
+    assert_eq!(backoffs, [5, 10, 20, 30, 30, 30]);
+    assert_eq!(tracker.recent_crashes(), 6);
+  }
+
+  #[test]
+  fn gives_up_after_max_crashes() {
+    let mut tracker = tracker(3);
+    let start = Instant::now();
+    assert!(tracker.record(start).is_some());
+    assert!(tracker.record(start + Duration::from_secs(60)).is_some());
+    assert_eq!(tracker.record(start + Duration::from_secs(120)), None);
+    assert_eq!(tracker.recent_crashes(), 3);
+  }
+
+  #[test]
+  fn crashes_expire_after_window() {
+    let mut tracker = tracker(3);
+    let start = Instant::now();
+    assert!(tracker.record(start).is_some());
+    assert!(tracker.record(start + Duration::from_secs(60)).is_some());
+    // The first crash has left the window, so the backoff only doubles once
+    let later = start + Duration::from_secs(30 * 60);
+    assert_eq!(tracker.record(later), Some(Duration::from_secs(10)));
+    assert_eq!(tracker.recent_crashes(), 2);
+  }
+}
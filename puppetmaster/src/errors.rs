@@ -0,0 +1,209 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use console::style;
+use puppet::{ConsoleLine, EventHandler, Puppet, StopOptions};
+use tokio::process::Command;
+use tokio::sync::watch;
+
+use std::convert::Infallible;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::util::jdk_tool;
+use crate::Error;
+
+/// The directory (relative to puppetmaster's working directory) that heap dumps are written to.
+const HEAP_DUMP_DIR: &str = "heap-dumps";
+const JMAP_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// What to do when the server runs into each kind of serious error.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ErrorsConfig {
+  pub out_of_memory: ErrorAction,
+  pub jvm_fatal_error: ErrorAction,
+  pub failed_to_bind_port: ErrorAction,
+  pub eula_not_accepted: ErrorAction,
+  pub world_corruption: ErrorAction
+}
+
+impl Default for ErrorsConfig {
+  fn default() -> Self {
+    ErrorsConfig {
+      out_of_memory: ErrorAction::HeapDumpRestart,
+      jvm_fatal_error: ErrorAction::Restart,
+      failed_to_bind_port: ErrorAction::Stop,
+      eula_not_accepted: ErrorAction::Stop,
+      world_corruption: ErrorAction::Restart
+    }
+  }
+}
+
+impl ErrorsConfig {
+  /// The error in `line` and the action configured for it,
+  /// or `None` if it is not an error or the error is ignored.
+  fn detect(&self, line: &ConsoleLine) -> Option<Detected> {
+    let (action, error) = match line {
+      ConsoleLine::OutOfMemory { reason: Some(reason) } => (self.out_of_memory, format!("Server ran out of memory ({})", reason)),
+      ConsoleLine::OutOfMemory { reason: None } => (self.out_of_memory, "Server ran out of memory".to_owned()),
+      ConsoleLine::JvmFatalError { report } => (self.jvm_fatal_error, format!("JVM crashed with a fatal error, see {}", report)),
+      ConsoleLine::FailedToBindPort => (self.failed_to_bind_port, "Server failed to bind to its port, it may already be in use".to_owned()),
+      ConsoleLine::EulaNotAccepted => (self.eula_not_accepted, "Server EULA has not been accepted, set `eula=true` in eula.txt".to_owned()),
+      ConsoleLine::WorldCorruption { message } => (self.world_corruption, format!("Server world may be corrupted ({})", message)),
+      _ => return None
+    };
+
+    // Saving a corrupted world could write the corruption back to disk
+    let save = !matches!(line, ConsoleLine::WorldCorruption { .. });
+    (action != ErrorAction::Ignore).then_some(Detected { action, error, save })
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorAction {
+  /// Carry on as if nothing happened.
+  Ignore,
+  /// Stop the server and start it again, backing off and giving up as configured under `[crash]`.
+  Restart,
+  /// Stop the server and exit puppetmaster with an error, so that the problem can be looked into.
+  Stop,
+  /// Take a heap dump of the server, then restart it as with `Restart`.
+  HeapDumpRestart
+}
+
+/// An error the server ran into, and what is being done about it.
+#[derive(Debug, Clone)]
+pub struct Detected {
+  pub action: ErrorAction,
+  pub error: String,
+  /// Whether the server may save the world while it is stopped, otherwise it is killed instead.
+  pub save: bool
+}
+
+impl Detected {
+  /// How the server is stopped because of this error. When the world must not be saved, the server is killed
+  /// straight away, as both `stop` and `SIGTERM` make it save on the way out.
+  fn stop_options(&self, config: &Config) -> StopOptions {
+    let options = config.stop_options();
+    match self.save {
+      true => options,
+      false => StopOptions { save_first: false, graceful: false, ..options }
+    }
+  }
+}
+
+/// Watches a single run of the server for serious errors, acting on the first one it runs into.
+#[derive(Debug)]
+pub struct ErrorMonitor<'a> {
+  config: &'a ErrorsConfig,
+  detected: watch::Sender<Option<Detected>>
+}
+
+impl<'a> ErrorMonitor<'a> {
+  pub fn new(config: &'a ErrorsConfig) -> Self {
+    ErrorMonitor { config, detected: watch::Sender::new(None) }
+  }
+
+  /// The first error the server ran into, if any.
+  pub fn detected(&self) -> Option<Detected> {
+    self.detected.borrow().clone()
+  }
+
+  /// Waits for the first error detected in the console, then takes a heap dump if the error's action asks for one
+  /// and stops the server, without saving the world if it may be corrupt. Whether the server is started again
+  /// is up to the caller, according to `ErrorMonitor::detected`.
+  pub async fn watch(&self, puppet: &Puppet, config: &Config) -> Result<Infallible, Error> {
+    let mut receiver = self.detected.subscribe();
+    let detected = receiver.wait_for(Option::is_some).await
+      .expect("sender is held by self")
+      .clone().unwrap();
+    let Detected { action, ref error, .. } = detected;
+
+    let message = match action {
+      ErrorAction::Stop => format!("[Puppetmaster] {}, stopping the server", error),
+      _ => format!("[Puppetmaster] {}, restarting the server", error)
+    };
+    println!("{}", style(message).red().bright());
+    if action == ErrorAction::HeapDumpRestart {
+      heap_dump(puppet, config).await?;
+    };
+
+    let outcome = puppet.stop(detected.stop_options(config)).await?;
+    println!("[Puppetmaster] Server {} ({})", outcome.stage, outcome.status);
+
+    std::future::pending().await
+  }
+}
+
+#[async_trait]
+impl EventHandler for ErrorMonitor<'_> {
+  async fn console_event(&self, _puppet: &Puppet, line: &ConsoleLine) {
+    if let Some(error) = self.config.detect(line) {
+      // Errors tend to come in bursts, only the first one is acted on
+      self.detected.send_if_modified(|detected| match detected {
+        Some(_) => false,
+        None => {
+          *detected = Some(error);
+          true
+        }
+      });
+    };
+  }
+}
+
+/// Takes a heap dump of the server with `jmap`, writing it to the `heap-dumps` directory.
+/// A failed heap dump is reported but otherwise ignored, since the server is restarted either way.
+async fn heap_dump(puppet: &Puppet, config: &Config) -> Result<(), Error> {
+  let id = match puppet.id() {
+    Some(id) => id,
+    None => return Ok(())
+  };
+
+  // The JVM writes the heap dump itself, relative to its own working directory
+  tokio::fs::create_dir_all(HEAP_DUMP_DIR).await?;
+  let path = std::env::current_dir()?.join(HEAP_DUMP_DIR)
+    .join(format!("heap-dump-{}.hprof", Local::now().format("%Y-%m-%d_%H-%M-%S")));
+  println!("[Puppetmaster] Taking a heap dump, this may take a while");
+  let jmap = Command::new(jdk_tool(config, "jmap"))
+    .arg(format!("-dump:format=b,file={}", path.display()))
+    .arg(id.to_string())
+    .kill_on_drop(true)
+    .output();
+  match tokio::time::timeout(JMAP_TIMEOUT, jmap).await {
+    Ok(Ok(output)) if output.status.success() && path.exists() => {
+      println!("[Puppetmaster] Heap dump written to {}", path.display());
+    },
+    Ok(Ok(output)) => println!("[Puppetmaster] jmap failed ({}), no heap dump was taken", output.status),
+    Ok(Err(err)) => println!("[Puppetmaster] Failed to run jmap, no heap dump was taken: {}", err),
+    Err(_) => println!("[Puppetmaster] jmap timed out, no heap dump was taken")
+  };
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn detect(line: &str) -> Detected {
+    ErrorsConfig::default().detect(&ConsoleLine::parse_from(line).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn saves_before_restarting() {
+    let config = Config::default();
+    let options = detect("[12:00:00] [Server thread/ERROR]: java.lang.OutOfMemoryError: Java heap space").stop_options(&config);
+    assert_eq!(options, config.stop_options());
+    assert!(options.save_first);
+    assert!(options.graceful);
+  }
+
+  #[test]
+  fn kills_without_saving_a_corrupt_world() {
+    let config = Config::default();
+    let options = detect("[12:00:00] [Server thread/FATAL]: Region file ./world/region/r.0.0.mca is truncated").stop_options(&config);
+    assert!(!options.save_first);
+    assert!(!options.graceful);
+  }
+}
@@ -1,8 +1,10 @@
 extern crate chrono;
+extern crate chrono_tz;
 extern crate dunce;
 extern crate puppet;
 #[macro_use]
 extern crate serde;
+extern crate serde_json;
 #[macro_use]
 extern crate thiserror;
 extern crate time;
@@ -10,21 +12,40 @@ extern crate tokio;
 extern crate toml;
 
 mod config;
+mod crash;
+mod errors;
+mod overload;
+mod resources;
+mod schedule;
+mod scheduler;
+mod state;
 mod util;
+mod vote;
+mod warning;
+mod watchdog;
 
 use chrono::prelude::*;
-use chrono::Duration;
 use console::{Term, style};
-use puppet::{Puppet, NoHandler};
+use puppet::{JvmPreset, MemorySize, Puppet, PuppetBuilder, StopOutcome, StopStage};
 use tokio::runtime::Builder;
 use tokio::time::Instant;
 
 use crate::config::Config;
+use crate::crash::CrashTracker;
+use crate::errors::{Detected, ErrorAction, ErrorMonitor};
+use crate::overload::OverloadMonitor;
+use crate::scheduler::{Change, PlannedRestart, Scheduler, SchedulerCommands};
+use crate::state::ServerState;
 use crate::util::AtomicFlag;
+use crate::vote::Votes;
+use crate::warning::{Bossbar, Delivery, Warning};
 
+use std::cmp::Reverse;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::io::{self, Write};
 use std::process;
+use std::time::Duration;
 
 fn main() {
   let result = Builder::new_multi_thread()
@@ -46,41 +67,85 @@ fn main() {
   };
 }
 
+/// How many of the upcoming scheduled restarts are shown when the server starts.
+const UPCOMING_RESTARTS: usize = 3;
+/// How late a warning can be and still be sent when a countdown starts.
+const WARNING_TOLERANCE: Duration = Duration::from_secs(1);
+
 #[inline]
 async fn run() -> Result<(), Error> {
   let config = Config::load("puppetmaster.toml").await?;
-  let parent = dunce::canonicalize(&config.jar_path)
-    .map_err(Error::InvalidJarPathCanonicalize)?
-    .parent().ok_or(Error::InvalidJarPath)?
-    .to_owned();
-  std::env::set_current_dir(parent)?;
+  resources::check_config(&config);
+  let builder = puppet_builder(&config)?;
+  let server_dir = server_dir(&config)?;
+  let mut crashes = CrashTracker::new(&config.crash);
+  let scheduler = Scheduler::new(config.schedule.clone());
+  let votes = Votes::new(&config.vote);
 
   loop {
-    let inst_now = Instant::now();
     let now = Utc::now();
-    let remaining = config.next_restart(now) - now;
-    let remaining_f = format!("{} hours, {} minutes", remaining.num_hours(), remaining.num_minutes());
+    let upcoming = config.schedule.upcoming(now, now, UPCOMING_RESTARTS);
+    scheduler.reset(now);
     println!("[Puppetmaster] Starting server");
-    println!("[Puppetmaster] Server scheduled to restart in {}", remaining_f);
+    match scheduler.planned().at {
+      Some(next) => {
+        let remaining = (next - now).to_std().unwrap_or_default();
+        println!("[Puppetmaster] Server scheduled to restart in {}", warning::format_remaining(remaining));
+        for &restart in &upcoming {
+          println!("[Puppetmaster]   {}", config.schedule.timezone.format(restart, "%a %Y-%m-%d %H:%M:%S %Z"));
+        };
+      },
+      None => println!("[Puppetmaster] No server restarts scheduled")
+    };
 
     let restart = AtomicFlag::new();
-    let puppet = Puppet::builder()
-      .jar_path(&config.jar_path)
-      .max_memory(&config.max_memory)
-      .min_memory(&config.min_memory)
-      .finish()?;
+    let interrupted = AtomicFlag::new();
+    let state = Arc::new(ServerState::default());
+    let overload = Arc::new(OverloadMonitor::new(&config.overload, &server_dir));
+    let errors = Arc::new(ErrorMonitor::new(&config.errors));
+    let puppet = builder.clone().finish()?;
+    // The monitors only ever return with an error, so they run for as long as the server does
     tokio::select!{
-      result = wait_and_restart(&puppet, &restart, inst_now, remaining) => match result {
+      result = wait_and_restart(&puppet, &state, &restart, &config, &scheduler) => match result {
         Err(err) => return Err(err),
         Ok(()) => continue
       },
-      result = puppet.start(NoHandler) => match result {
+      result = puppet.start((state.clone(), overload.clone(), errors.clone(), SchedulerCommands::new(&scheduler), votes.handler(&state, &scheduler))) => match result {
         Err(err) => return Err(err.into()),
-        Ok(()) => match restart.get() {
-          true => continue,
-          false => break
-        }
+        Ok(()) => if restart.get() { continue }
+      },
+      Err(err) = watchdog::watchdog(&puppet, &state, &restart, &config) => return Err(err),
+      Err(err) = overload.watch(&puppet, &scheduler) => return Err(err),
+      Err(err) = resources::monitor(&puppet, &scheduler, &config) => return Err(err),
+      Err(err) = errors.watch(&puppet, &config) => return Err(err),
+      Err(err) = votes.watch(&puppet) => return Err(err),
+      result = stop_on_interrupt(&puppet, &interrupted, &config) => { result?; break },
+    };
+
+    let status = puppet.wait().await?;
+    if interrupted.get() { break };
+    match errors.detected() {
+      Some(Detected { action: ErrorAction::Stop, error, .. }) => return Err(Error::ServerError(error)),
+      // Restarts because of an error back off like crashes do, so that a server that keeps running into it is given up on
+      Some(_) => (),
+      None => {
+        // The server was stopped deliberately, such as with the `stop` command,
+        // which servers that do not announce that they are stopping still have to be sent
+        if status.success() && (state.stopping.get() || puppet.stop_sent()) { break };
+        println!("{}", style(format!("[Puppetmaster] Server crashed ({})", status)).red().bright());
+        if !config.crash.auto_restart { break };
+      }
+    };
+
+    match crashes.record(Instant::now()) {
+      Some(backoff) => {
+        println!("[Puppetmaster] Restarting in {}", warning::format_remaining(backoff));
+        tokio::select!{
+          () = tokio::time::sleep(backoff) => (),
+          result = tokio::signal::ctrl_c() => { result?; break }
+        };
       },
+      None => return Err(Error::CrashLoop(crashes.recent_crashes(), config.crash.crash_window))
     };
   }
 
@@ -89,48 +154,205 @@ async fn run() -> Result<(), Error> {
   Ok(())
 }
 
-#[derive(Debug)]
-enum Warning {
-  Remaining(u32),
-  RestartingNow
+/// Stops the server once puppetmaster is interrupted with Ctrl-C, which the server does not receive itself
+/// as it runs in its own process group, killing it instead if interrupted again while it stops.
+async fn stop_on_interrupt(puppet: &Puppet, interrupted: &AtomicFlag, config: &Config) -> Result<(), Error> {
+  tokio::signal::ctrl_c().await?;
+  interrupted.set();
+  println!("[Puppetmaster] Interrupted, stopping the server (interrupt again to kill it)");
+  let outcome = tokio::select!{
+    result = puppet.stop(config.stop_options()) => result?,
+    result = tokio::signal::ctrl_c() => {
+      result?;
+      puppet.kill().await?;
+      StopOutcome { stage: StopStage::Killed, status: puppet.wait().await? }
+    }
+  };
+
+  println!("[Puppetmaster] Server {} ({})", outcome.stage, outcome.status);
+  Ok(())
 }
 
-async fn wait_and_restart(puppet: &Puppet, restart: &AtomicFlag, now: Instant, remaining: Duration) -> Result<(), Error> {
-  let warnings = [
-    (Warning::Remaining(30), time_remaining_minus(now, remaining, 30)),
-    (Warning::Remaining(10), time_remaining_minus(now, remaining, 10)),
-    (Warning::Remaining(5), time_remaining_minus(now, remaining, 5)),
-    (Warning::Remaining(1), time_remaining_minus(now, remaining, 1)),
-    (Warning::RestartingNow, Some(time_remaining(now, remaining)))
-  ];
-
-  for (warning, instant) in warnings {
-    if let Some(instant) = instant {
-      tokio::time::sleep_until(instant).await;
-
-      match warning {
-        Warning::Remaining(mins) => {
-          puppet.command(format!("say {} minutes until server restart", mins)).await?;
-        },
-        Warning::RestartingNow => {
-          restart.set();
-          puppet.command("stop").await?;
-          puppet.wait().await?;
-        }
-      };
+/// Prepares a builder for launching the server as configured, which can be reused for every restart.
+fn puppet_builder(config: &Config) -> Result<PuppetBuilder, Error> {
+  let mut builder = Puppet::builder()
+    .envs(&config.env)
+    .pty(config.pty);
+  if let Some(command_prefix) = &config.command_prefix {
+    builder = builder.command_prefix(command_prefix);
+  };
+  if let Some(launch) = &config.launch {
+    let builder = builder.command(&launch.program, &launch.args);
+    return Ok(match &config.working_dir {
+      Some(working_dir) => builder.current_dir(working_dir),
+      None => builder
+    });
+  };
+
+  config.validate_memory()?;
+  let jar_path = dunce::canonicalize(&config.jar_path)
+    .map_err(Error::InvalidJarPathCanonicalize)?;
+  let working_dir = server_dir(config)?;
+
+  let mut builder = builder
+    .jar_path(&jar_path)
+    .current_dir(&working_dir)
+    .max_memory(config.max_memory)
+    .min_memory(config.min_memory)
+    .gc_logging(config.gc_logging)
+    .jvm_args(&config.jvm_args)
+    .server_args(&config.server_args);
+  if let Some(java) = &config.java {
+    builder = builder.java(java);
+  };
+  if let Some(jvm_preset) = &config.jvm_preset {
+    builder = builder.jvm_preset(jvm_preset.parse::<JvmPreset>()?);
+  };
+
+  Ok(builder)
+}
+
+/// The directory the server runs in, which holds its files such as `ops.json`.
+fn server_dir(config: &Config) -> Result<PathBuf, Error> {
+  if let Some(working_dir) = &config.working_dir {
+    return Ok(working_dir.clone());
+  };
+
+  match &config.launch {
+    Some(_) => Ok(PathBuf::from(".")),
+    None => {
+      let jar_path = dunce::canonicalize(&config.jar_path)
+        .map_err(Error::InvalidJarPathCanonicalize)?;
+      Ok(jar_path.parent().ok_or(Error::InvalidJarPath)?.to_owned())
+    }
+  }
+}
+
+/// Restarts the server when planned by the scheduler, following it as the planned restart is moved around.
+/// Never returns if there is no restart planned.
+async fn wait_and_restart(puppet: &Puppet, state: &ServerState, restart: &AtomicFlag, config: &Config, scheduler: &Scheduler) -> Result<(), Error> {
+  let mut planned = scheduler.subscribe();
+  let mut bossbar = None;
+  loop {
+    let PlannedRestart { at, change } = *planned.borrow_and_update();
+    announce(puppet, config, at, change).await?;
+    let rescheduled = tokio::select!{
+      result = restart_at(puppet, state, config, at, change, &mut bossbar) => { result?; false },
+      Ok(()) = planned.changed() => true
     };
+
+    // The countdown bossbar is removed both when the restart is due and when it is rescheduled
+    if let Some(bossbar) = bossbar.take() {
+      bossbar.remove(puppet).await?;
+    };
+
+    if !rescheduled { break };
+  };
+
+  restart.set();
+  let outcome = puppet.stop(config.stop_options()).await?;
+  println!("[Puppetmaster] Server {} ({})", outcome.stage, outcome.status);
+
+  Ok(())
+}
+
+/// Waits until the restart planned for `at` is due, deferring scheduled restarts until the server is empty if configured to.
+async fn restart_at<'a>(
+  puppet: &Puppet,
+  state: &ServerState,
+  config: &'a Config,
+  at: Option<DateTime<Utc>>,
+  change: Change,
+  bossbar: &mut Option<Bossbar<'a>>
+) -> Result<(), Error> {
+  let at = match at {
+    Some(at) => at,
+    None => return std::future::pending().await
+  };
+
+  match config.schedule.defer_until_empty {
+    Some(window) if matches!(change, Change::Scheduled | Change::Cancelled) => {
+      tokio::time::sleep_until(instant_at(at)).await;
+      if tokio::time::timeout(window, state.empty()).await.is_ok() {
+        println!("[Puppetmaster] No players online, restarting now");
+      } else {
+        let countdown = config.warnings.iter().map(|warning| warning.before).max().unwrap_or_default();
+        let players = state.player_count();
+        let players = format!("{} player{}", players, if players == 1 { "" } else { "s" });
+        println!("[Puppetmaster] {} still online, restarting in {}", players, warning::format_remaining(countdown));
+        let at = Utc::now() + chrono::Duration::from_std(countdown).unwrap();
+        countdown_to(puppet, config, Instant::now() + countdown, at, bossbar).await?;
+      };
+    },
+    _ => countdown_to(puppet, config, instant_at(at), at, bossbar).await?
+  };
+
+  Ok(())
+}
+
+/// Tells the console and players about a change to the planned restart.
+async fn announce(puppet: &Puppet, config: &Config, at: Option<DateTime<Utc>>, change: Change) -> Result<(), Error> {
+  let format = |at| config.schedule.timezone.format(at, "%a %H:%M");
+  let message = match (change, at) {
+    (Change::Scheduled, _) => return Ok(()),
+    (Change::Moved, Some(at)) => match (at - Utc::now()).to_std() {
+      Ok(remaining) if remaining.as_secs() > 0 => format!("Server restart moved, restarting in {}", warning::format_remaining(remaining)),
+      _ => "Server restarting now".to_owned()
+    },
+    (Change::Postponed, Some(at)) => format!("Server restart postponed to {}", format(at)),
+    (Change::Cancelled, Some(at)) => format!("Server restart cancelled, the next one is at {}", format(at)),
+    (_, None) => "Server restart cancelled".to_owned()
   };
 
+  println!("[Puppetmaster] {}", message);
+  puppet.command(format!("say {}", message)).await?;
   Ok(())
 }
 
-// Returns `None` when the resulting instant would be prior in time than `t`
-fn time_remaining_minus(t: Instant, remaining: Duration, a: u32) -> Option<Instant> {
-  (remaining - Duration::minutes(a as i64)).to_std().ok().map(|d| t + d)
+/// Sends the configured warnings ahead of a restart at `deadline`, returning once it is due.
+/// `at` is the same moment as a time of day, which the warnings show to players.
+/// The countdown bossbar is left in `bossbar` for the caller to remove.
+async fn countdown_to<'a>(
+  puppet: &Puppet,
+  config: &'a Config,
+  deadline: Instant,
+  at: DateTime<Utc>,
+  bossbar: &mut Option<Bossbar<'a>>
+) -> Result<(), Error> {
+  let time = config.schedule.timezone.format(at, "%H:%M");
+  // Warnings that were due before the server started are skipped, while a warning that is due right
+  // now, such as the first warning of a countdown that starts immediately, is still sent
+  let now = Instant::now();
+  let mut warnings = config.warnings.iter()
+    .filter(|warning| deadline.checked_sub(warning.before).is_some_and(|instant| instant + WARNING_TOLERANCE >= now))
+    .collect::<Vec<&Warning>>();
+  warnings.sort_by_key(|warning| Reverse(warning.before));
+  let mut warnings = warnings.into_iter().peekable();
+  loop {
+    let now = Instant::now();
+    let next_warning = warnings.peek().map(|warning| deadline - warning.before);
+    let next_update = bossbar.as_ref().map(|bossbar| now + bossbar.next_update(deadline - now));
+    let wake = next_warning.into_iter().chain(next_update).fold(deadline, Instant::min);
+    tokio::time::sleep_until(wake).await;
+    if wake >= deadline { break };
+
+    if next_warning == Some(wake) {
+      let warning = warnings.next().unwrap();
+      match (warning.delivery, &mut *bossbar) {
+        (Delivery::Bossbar, Some(bossbar)) => bossbar.replace(puppet, warning, &time).await?,
+        (Delivery::Bossbar, None) => *bossbar = Some(Bossbar::create(puppet, warning, &time).await?),
+        _ => warning.deliver(puppet, &time).await?
+      };
+    } else if let Some(bossbar) = bossbar {
+      bossbar.update(puppet, deadline.saturating_duration_since(Instant::now()), &time).await?;
+    };
+  };
+
+  Ok(())
 }
 
-fn time_remaining(t: Instant, remaining: Duration) -> Instant {
-  t + remaining.to_std().unwrap()
+fn instant_at(datetime: DateTime<Utc>) -> Instant {
+  Instant::now() + (datetime - Utc::now()).to_std().unwrap_or_default()
 }
 
 #[derive(Debug, Error)]
@@ -149,4 +371,18 @@ pub enum Error {
   InvalidJarPathCanonicalize(std::io::Error),
   #[error("Error: Invalid jarfile path")]
   InvalidJarPath,
+  #[error("Error: Server crashed {0} times within {}, giving up", warning::format_remaining(*.1))]
+  CrashLoop(usize, Duration),
+  #[error("Error: {0}")]
+  ServerError(String),
+  #[error("Config Error: {0}")]
+  UnknownJvmPreset(#[from] puppet::UnknownJvmPreset),
+  #[error("Config Error: Memory size {0} could not be resolved, as this system's total memory is unknown")]
+  UnknownSystemMemory(MemorySize),
+  #[error("Config Error: min-memory ({0}) is larger than max-memory ({1})")]
+  MinMemoryExceedsMax(MemorySize, MemorySize),
+  #[error("Config Error: max-memory ({0}) is more than this system's total memory ({1:.1}G)")]
+  InsufficientMemory(MemorySize, f64),
+  #[error("Config Error: vote.threshold ({0}) must be more than 0 and at most 1")]
+  InvalidVoteThreshold(f64),
 }
@@ -0,0 +1,239 @@
+use async_trait::async_trait;
+use console::style;
+use puppet::{Color, ConsoleLine, EventHandler, Puppet, Text};
+use tokio::time::Instant;
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::asyncify;
+use crate::scheduler::Scheduler;
+use crate::util::Sustained;
+use crate::warning::format_remaining;
+use crate::Error;
+
+/// How often the health score is checked against the configured thresholds.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Watching for a server that is overloaded, from the "Can't keep up!" lines it logs.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OverloadConfig {
+  /// How far back overload reports are counted, such as `"5m"`.
+  #[serde(with = "humantime_serde")]
+  pub window: Duration,
+  /// How many reports within the window bring the health score down to 0.
+  pub max_reports: u32,
+  /// How many milliseconds behind in total within the window bring the health score down to 0.
+  pub max_ms_behind: u64,
+  /// How long the health score must stay below a threshold before acting on it.
+  #[serde(with = "humantime_serde")]
+  pub sustained: Duration,
+  /// Warns the server's ops once the health score stays below this, such as `0.5`.
+  pub warn_below: Option<f64>,
+  /// Restarts the server once the health score stays below this.
+  pub restart_below: Option<f64>,
+  /// How long the server counts down before an overload restart.
+  #[serde(with = "humantime_serde")]
+  pub countdown: Duration
+}
+
+impl Default for OverloadConfig {
+  fn default() -> Self {
+    OverloadConfig {
+      window: Duration::from_secs(5 * 60),
+      max_reports: 30,
+      max_ms_behind: 60_000,
+      sustained: Duration::from_secs(2 * 60),
+      warn_below: Some(0.5),
+      restart_below: None,
+      countdown: Duration::from_secs(60)
+    }
+  }
+}
+
+/// Tracks how overloaded the server is during a single run, over a sliding window.
+#[derive(Debug)]
+pub struct OverloadMonitor<'a> {
+  config: &'a OverloadConfig,
+  /// The server's directory, which holds `ops.json`.
+  server_dir: PathBuf,
+  /// When each overload report in the window was logged, and how many milliseconds behind it was.
+  reports: Mutex<VecDeque<(Instant, u32)>>
+}
+
+impl<'a> OverloadMonitor<'a> {
+  pub fn new(config: &'a OverloadConfig, server_dir: impl Into<PathBuf>) -> Self {
+    OverloadMonitor { config, server_dir: server_dir.into(), reports: Mutex::new(VecDeque::new()) }
+  }
+
+  /// The server's health from 1 (no overload reports) to 0, whichever is worse of how often
+  /// it reported being overloaded and how far behind it fell in total, within the window ending at `now`.
+  pub fn health(&self, now: Instant) -> f64 {
+    let mut reports = self.reports.lock().unwrap();
+    self.expire(&mut reports, now);
+    let frequency = reports.len() as f64 / self.config.max_reports.max(1) as f64;
+    let lag = reports.iter().map(|&(_, ms_behind)| ms_behind as f64).sum::<f64>() / self.config.max_ms_behind.max(1) as f64;
+    (1.0 - frequency.max(lag)).clamp(0.0, 1.0)
+  }
+
+  fn record(&self, ms_behind: u32, now: Instant) {
+    let mut reports = self.reports.lock().unwrap();
+    self.expire(&mut reports, now);
+    reports.push_back((now, ms_behind));
+  }
+
+  fn expire(&self, reports: &mut VecDeque<(Instant, u32)>, now: Instant) {
+    while reports.front().is_some_and(|&(at, _)| now.saturating_duration_since(at) > self.config.window) {
+      reports.pop_front();
+    };
+  }
+
+  /// Checks the health score every few seconds, warning ops once it stays below `overload.warn-below`
+  /// for `overload.sustained`, and restarting the server within `overload.countdown` once it stays below
+  /// `overload.restart-below` for as long.
+  pub async fn watch(&self, puppet: &Puppet, scheduler: &Scheduler) -> Result<Infallible, Error> {
+    let mut warn = Sustained::default();
+    let mut restart = Sustained::default();
+    loop {
+      tokio::time::sleep(CHECK_INTERVAL).await;
+      let now = Instant::now();
+      let health = self.health(now);
+      if warn.update(below(self.config.warn_below, health), now, self.config.sustained) {
+        let message = format!("Server is overloaded (health {:.0}%)", health * 100.0);
+        println!("{}", style(format!("[Puppetmaster] {}", message)).yellow().bright());
+        self.warn_ops(puppet, &message).await?;
+      };
+
+      if restart.update(below(self.config.restart_below, health), now, self.config.sustained) {
+        let message = format!("[Puppetmaster] Server has been overloaded for {} (health {:.0}%), restarting it",
+          format_remaining(self.config.sustained), health * 100.0);
+        println!("{}", style(message).red().bright());
+        scheduler.restart_within(self.config.countdown);
+      };
+    }
+  }
+
+  /// Sends a message to every op listed in the server's `ops.json`.
+  async fn warn_ops(&self, puppet: &Puppet, message: &str) -> Result<(), Error> {
+    let ops = match read_ops(self.server_dir.join("ops.json")).await {
+      Ok(ops) => ops,
+      Err(err) => {
+        println!("[Puppetmaster] Failed to read the server's ops: {}", err);
+        return Ok(());
+      }
+    };
+
+    let text = Text::new(format!("[Puppetmaster] {}", message)).color(Color::Gold);
+    for op in ops {
+      // Offline ops are rejected by the server, which is harmless
+      puppet.tellraw(&op.name, &text).await?;
+    };
+
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl EventHandler for OverloadMonitor<'_> {
+  async fn console_event(&self, _puppet: &Puppet, line: &ConsoleLine) {
+    if let ConsoleLine::Overloaded { ms_behind, .. } = *line {
+      self.record(ms_behind, Instant::now());
+    };
+  }
+}
+
+/// Whether `health` is below `threshold`, if there is one.
+fn below(threshold: Option<f64>, health: f64) -> bool {
+  threshold.is_some_and(|threshold| health < threshold)
+}
+
+/// An entry of the server's `ops.json`.
+#[derive(Debug, Deserialize)]
+struct Op {
+  name: String
+}
+
+async fn read_ops(path: impl AsRef<Path>) -> Result<Vec<Op>, Error> {
+  let path = path.as_ref().to_owned();
+  asyncify(move || {
+    let data = std::fs::read(path)?;
+    serde_json::from_slice(&data).map_err(|err| Error::Io(err.into()))
+  }).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SECOND: Duration = Duration::from_secs(1);
+
+  fn config() -> OverloadConfig {
+    OverloadConfig { window: 60 * SECOND, max_reports: 10, max_ms_behind: 10_000, ..OverloadConfig::default() }
+  }
+
+  #[test]
+  fn healthy_without_reports() {
+    let config = config();
+    let monitor = OverloadMonitor::new(&config, ".");
+    assert_eq!(monitor.health(Instant::now()), 1.0);
+  }
+
+  #[test]
+  fn health_from_report_frequency() {
+    let config = config();
+    let monitor = OverloadMonitor::new(&config, ".");
+    let now = Instant::now();
+    for _ in 0..5 {
+      monitor.record(100, now);
+    };
+    assert_eq!(monitor.health(now), 0.5);
+  }
+
+  #[test]
+  fn health_from_time_behind() {
+    let config = config();
+    let monitor = OverloadMonitor::new(&config, ".");
+    let now = Instant::now();
+    monitor.record(2_000, now);
+    monitor.record(5_500, now);
+    // Two reports alone would only take the health down to 0.8
+    assert_eq!(monitor.health(now), 0.25);
+  }
+
+  #[test]
+  fn health_bottoms_out_at_zero() {
+    let config = config();
+    let monitor = OverloadMonitor::new(&config, ".");
+    let now = Instant::now();
+    for _ in 0..20 {
+      monitor.record(5_000, now);
+    };
+    assert_eq!(monitor.health(now), 0.0);
+  }
+
+  #[test]
+  fn reports_expire_after_window() {
+    let config = config();
+    let monitor = OverloadMonitor::new(&config, ".");
+    let start = Instant::now();
+    monitor.record(5_000, start);
+    monitor.record(5_000, start + 30 * SECOND);
+    assert_eq!(monitor.health(start + 60 * SECOND), 0.0);
+    assert_eq!(monitor.health(start + 61 * SECOND), 0.5);
+    assert_eq!(monitor.health(start + 91 * SECOND), 1.0);
+  }
+
+  #[test]
+  fn thresholds() {
+    assert!(!below(None, 0.0));
+    assert!(below(Some(0.5), 0.25));
+    assert!(!below(Some(0.5), 0.5));
+    assert!(!below(Some(0.5), 1.0));
+    // A threshold of 0 is never reached, as the health bottoms out at 0
+    assert!(!below(Some(0.0), 0.0));
+  }
+}
@@ -0,0 +1,276 @@
+use console::style;
+use puppet::{MemorySize, Puppet};
+use tokio::time::Instant;
+
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+use crate::config::{asyncify, Config};
+use crate::scheduler::Scheduler;
+use crate::util::Sustained;
+use crate::warning::format_remaining;
+use crate::Error;
+
+/// The rate at which `/proc/<pid>/stat` counts CPU time, which Linux fixes at 100 on every architecture.
+const USER_HZ: f64 = 100.0;
+
+/// Sampling the server process's resource usage from `/proc/<pid>`, which is only available on Linux.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ResourcesConfig {
+  pub enabled: bool,
+  /// How often to take a sample, such as `"1m"`.
+  #[serde(with = "humantime_serde")]
+  pub interval: Duration,
+  /// Whether to print every sample to the console.
+  pub log: bool,
+  /// How much memory the JVM is expected to use beyond `max-memory`, for rules given as a percentage.
+  pub memory_overhead: MemorySize,
+  /// How long the server counts down before a restart caused by a rule.
+  #[serde(with = "humantime_serde")]
+  pub countdown: Duration,
+  /// Given as `[[resources.rules]]`.
+  pub rules: Vec<ResourceRule>
+}
+
+impl Default for ResourcesConfig {
+  fn default() -> Self {
+    ResourcesConfig {
+      enabled: false,
+      interval: Duration::from_secs(60),
+      log: true,
+      memory_overhead: MemorySize::gigabytes(1),
+      countdown: Duration::from_secs(5 * 60),
+      rules: vec![ResourceRule {
+        rss_above: Some(MemorySize::Percent(95.0)),
+        cpu_above: None,
+        threads_above: None,
+        fds_above: None,
+        duration: Duration::from_secs(10 * 60)
+      }]
+    }
+  }
+}
+
+/// Restarts the server once all of the limits given in the rule have been exceeded for `for`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ResourceRule {
+  /// Resident memory, either a size such as `"6G"`, or a percentage of `max-memory` plus
+  /// `memory-overhead`, such as `"95%"`.
+  pub rss_above: Option<MemorySize>,
+  /// CPU usage as a percentage of a single core, such as `350.0` for three and a half cores.
+  pub cpu_above: Option<f64>,
+  pub threads_above: Option<u64>,
+  /// Open file descriptors, which include network connections.
+  pub fds_above: Option<u64>,
+  /// Such as `"10m"`.
+  #[serde(rename = "for", with = "humantime_serde")]
+  pub duration: Duration
+}
+
+impl ResourceRule {
+  /// Describes the limits exceeded by `usage` if all of this rule's limits were exceeded.
+  /// `memory_limit` is what `rss-above` percentages are relative to, if it is known.
+  fn exceeded(&self, usage: &Usage, memory_limit: Option<u64>) -> Option<String> {
+    let rss_above = match self.rss_above {
+      Some(MemorySize::Percent(percent)) => Some((memory_limit? as f64 * percent / 100.0) as u64),
+      Some(MemorySize::Bytes(bytes)) => Some(bytes),
+      None => None
+    };
+
+    let mut exceeded = Vec::new();
+    if let Some(rss_above) = rss_above {
+      if usage.sample.rss <= rss_above { return None };
+      exceeded.push(format!("memory above {}", format_bytes(rss_above)));
+    };
+    if let Some(cpu_above) = self.cpu_above {
+      if usage.cpu? <= cpu_above { return None };
+      exceeded.push(format!("CPU above {:.0}%", cpu_above));
+    };
+    if let Some(threads_above) = self.threads_above {
+      if usage.sample.threads <= threads_above { return None };
+      exceeded.push(format!("more than {} threads", threads_above));
+    };
+    if let Some(fds_above) = self.fds_above {
+      if usage.sample.fds <= fds_above { return None };
+      exceeded.push(format!("more than {} open files", fds_above));
+    };
+
+    // A rule without any limits never applies
+    (!exceeded.is_empty()).then(|| exceeded.join(", "))
+  }
+}
+
+/// The resource usage of a process at one point in time, read from `/proc/<pid>`.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+  /// Resident memory in bytes.
+  pub rss: u64,
+  /// CPU time spent in user and kernel mode since the process started.
+  pub cpu_time: Duration,
+  pub threads: u64,
+  /// Open file descriptors.
+  pub fds: u64
+}
+
+impl Sample {
+  pub async fn read(pid: u32) -> Result<Sample, Error> {
+    asyncify(move || Ok(Sample::read_blocking(pid)?)).await
+  }
+
+  fn read_blocking(pid: u32) -> io::Result<Sample> {
+    let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, format!("malformed /proc/{}/{}", pid, what));
+
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+    let (rss, threads) = parse_status(&status).ok_or_else(|| invalid("status"))?;
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    let cpu_time = parse_stat(&stat).ok_or_else(|| invalid("stat"))?;
+    let fds = fs::read_dir(format!("/proc/{}/fd", pid))?.count() as u64;
+
+    Ok(Sample { rss, cpu_time, threads, fds })
+  }
+}
+
+/// Reads the resident memory in bytes and the number of threads from the contents of `/proc/<pid>/status`.
+fn parse_status(status: &str) -> Option<(u64, u64)> {
+  let field = |name: &str| status.lines()
+    .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+    .and_then(|value| value.split_whitespace().next()?.parse::<u64>().ok());
+  // Kernel threads and zombies have no `VmRSS`
+  let rss = field("VmRSS").unwrap_or(0) * 1024;
+  Some((rss, field("Threads")?))
+}
+
+/// Reads the CPU time spent in user and kernel mode from the contents of `/proc/<pid>/stat`.
+fn parse_stat(stat: &str) -> Option<Duration> {
+  // The process name is in parentheses and may itself contain spaces or parentheses
+  let fields = stat.rsplit_once(')')?.1.split_whitespace().collect::<Vec<&str>>();
+  // `utime` and `stime` are the 14th and 15th fields, counting the pid and name
+  let ticks = |index: usize| fields.get(index)?.parse::<u64>().ok();
+  Some(Duration::from_secs_f64((ticks(11)? + ticks(12)?) as f64 / USER_HZ))
+}
+
+/// A sample along with the CPU usage since the previous one.
+struct Usage {
+  sample: Sample,
+  /// As a percentage of a single core, unknown for the first sample.
+  cpu: Option<f64>
+}
+
+/// Warns that rules with an `rss-above` percentage are disabled when the server is started by `[launch]`,
+/// as the percentage is relative to `max-memory`, which the launch command does not use.
+pub fn check_config(config: &Config) {
+  let percent_rules = config.resources.rules.iter()
+    .any(|rule| matches!(rule.rss_above, Some(MemorySize::Percent(_))));
+  if config.resources.enabled && config.launch.is_some() && percent_rules {
+    println!("[Puppetmaster] Config Warning: `rss-above` percentages are relative to `max-memory`, which `[launch]` does not use, so rules with one are disabled");
+  };
+}
+
+/// Samples the server process's resource usage every `resources.interval`, logging it if `resources.log` is set,
+/// and restarting the server once a rule from `resources.rules` has applied for its `for` duration.
+/// Stops sampling once the process can no longer be read, such as after it exits.
+pub async fn monitor(puppet: &Puppet, scheduler: &Scheduler, config: &Config) -> Result<Infallible, Error> {
+  let resources = &config.resources;
+  let pid = match puppet.id() {
+    Some(pid) if resources.enabled => pid,
+    _ => return std::future::pending().await
+  };
+
+  // Without a known limit, rules with an `rss-above` percentage never apply, see `check_config`
+  let memory_limit = config.max_memory.to_bytes().zip(resources.memory_overhead.to_bytes())
+    .map(|(max_memory, overhead)| max_memory + overhead)
+    .filter(|_| config.launch.is_none());
+  let mut rules = resources.rules.iter()
+    .map(|rule| (rule, Sustained::default()))
+    .collect::<Vec<_>>();
+  let mut previous: Option<(Instant, Duration)> = None;
+  loop {
+    tokio::time::sleep(resources.interval).await;
+    let sample = match Sample::read(pid).await {
+      Ok(sample) => sample,
+      Err(err) => {
+        // The server has most likely exited, otherwise this system does not support sampling
+        println!("[Puppetmaster] Failed to sample the server's resource usage, no longer sampling it: {}", err);
+        return std::future::pending().await;
+      }
+    };
+
+    let now = Instant::now();
+    let cpu = previous.map(|(at, cpu_time)| {
+      sample.cpu_time.saturating_sub(cpu_time).as_secs_f64() / (now - at).as_secs_f64() * 100.0
+    });
+    previous = Some((now, sample.cpu_time));
+    let usage = Usage { sample, cpu };
+
+    if resources.log {
+      let mut message = format!("[Puppetmaster] Memory {}", format_bytes(sample.rss));
+      if let Some(cpu) = cpu {
+        write!(message, ", CPU {:.1}%", cpu).unwrap();
+      };
+      write!(message, ", {} threads, {} open files", sample.threads, sample.fds).unwrap();
+      println!("{}", message);
+    };
+
+    for (rule, sustained) in &mut rules {
+      let exceeded = rule.exceeded(&usage, memory_limit);
+      if sustained.update(exceeded.is_some(), now, rule.duration) {
+        let message = format!("[Puppetmaster] Server has been using {} for {}, restarting it",
+          exceeded.unwrap(), format_remaining(rule.duration));
+        println!("{}", style(message).red().bright());
+        scheduler.restart_within(resources.countdown);
+      };
+    };
+  }
+}
+
+/// Formats an amount of memory with one decimal place in the largest fitting unit, such as `1.5G`.
+fn format_bytes(bytes: u64) -> String {
+  let units = [("T", 1u64 << 40), ("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+  match units.iter().find(|&&(_, size)| bytes >= size) {
+    Some(&(suffix, size)) => format!("{:.1}{}", bytes as f64 / size as f64, suffix),
+    None => format!("{}B", bytes)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const STATUS: &str = "Name:\tjava\nUmask:\t0022\nState:\tS (sleeping)\nVmPeak:\t 9876544 kB\n\
+    VmRSS:\t 4194304 kB\nRssAnon:\t 4000000 kB\nThreads:\t57\nSigQ:\t0/63455\n";
+
+  #[test]
+  fn parse_status_fields() {
+    assert_eq!(parse_status(STATUS), Some((4 << 30, 57)));
+    // Kernel threads and zombies have no `VmRSS`
+    assert_eq!(parse_status("Name:\tkthreadd\nThreads:\t1\n"), Some((0, 1)));
+    assert_eq!(parse_status("Name:\tjava\nVmRSS:\t 1024 kB\n"), None);
+    // Fields are matched by their whole name
+    assert_eq!(parse_status("VmRSSX:\t1 kB\nThreadsX:\t2\n"), None);
+  }
+
+  #[test]
+  fn parse_stat_cpu_time() {
+    let stat = "1234 (java) S 1 1234 1234 0 -1 4194560 95000 0 12 0 1500 250 0 0 20 0 57 0 1000 0 0";
+    assert_eq!(parse_stat(stat), Some(Duration::from_millis(17_500)));
+    // The name may contain spaces and parentheses
+    let stat = "1234 (my (weird) java) S 1 1234 1234 0 -1 4194560 95000 0 12 0 1500 250 0 0 20 0 57 0";
+    assert_eq!(parse_stat(stat), Some(Duration::from_millis(17_500)));
+    assert_eq!(parse_stat("1234 (java) S 1 1234"), None);
+    assert_eq!(parse_stat("1234 java S"), None);
+  }
+
+  #[test]
+  fn format_bytes_units() {
+    assert_eq!(format_bytes(0), "0B");
+    assert_eq!(format_bytes(1023), "1023B");
+    assert_eq!(format_bytes(1024), "1.0K");
+    assert_eq!(format_bytes(1536 << 20), "1.5G");
+    assert_eq!(format_bytes(3 << 40), "3.0T");
+  }
+}
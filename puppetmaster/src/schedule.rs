@@ -0,0 +1,377 @@
+use chrono::prelude::*;
+use chrono::{Duration, LocalResult};
+use chrono_tz::Tz;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// When the server should be restarted. Every configured rule contributes occurrences,
+/// and the server is restarted at whichever comes first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Schedule {
+  /// The timezone that `times`, `days` and `cron` are given in.
+  pub timezone: Timezone,
+  /// Times of day to restart at, such as `"04:00:00"`.
+  pub times: Vec<NaiveTime>,
+  /// The days of the week that `times` apply to, such as `"Mon"`, or every day if empty.
+  pub days: Vec<Weekday>,
+  /// Cron expressions to restart at, either the standard 5 fields (`minute hour day month weekday`)
+  /// or 6 or 7 fields starting with seconds (and ending with an optional year).
+  /// Days of the week are numbered from 0 (Sunday) to 6 (Saturday) in standard expressions, as in a crontab,
+  /// but from 1 (Sunday) to 7 (Saturday) in expressions with seconds, so names such as `Mon` are clearer.
+  pub cron: Vec<CronSchedule>,
+  /// Restart the server once it has been running for this long, such as `"24h"`.
+  #[serde(with = "humantime_serde")]
+  pub max_uptime: Option<std::time::Duration>,
+  /// When a restart is due, wait up to this long (such as `"30m"`) for all players to leave,
+  /// restarting as soon as the server is empty. Once it runs out, the usual warnings count down to the restart.
+  #[serde(with = "humantime_serde")]
+  pub defer_until_empty: Option<std::time::Duration>
+}
+
+impl Schedule {
+  /// The next restart strictly after `now`, for a server that was started at `started`.
+  /// Returns `None` if no restarts are scheduled.
+  pub fn next_restart(&self, now: DateTime<Utc>, started: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let next = match self.timezone {
+      Timezone::Local => self.next_wall_clock(now.with_timezone(&Local)),
+      Timezone::Named(tz) => self.next_wall_clock(now.with_timezone(&tz))
+    };
+
+    let max_uptime = self.max_uptime
+      .and_then(|max_uptime| Duration::from_std(max_uptime).ok())
+      .and_then(|max_uptime| started.checked_add_signed(max_uptime))
+      .filter(|&max_uptime| max_uptime > now);
+    next.into_iter().chain(max_uptime).min()
+  }
+
+  /// Up to `count` of the next restarts after `now`, in order, for a server that was started at `started`.
+  pub fn upcoming(&self, now: DateTime<Utc>, started: DateTime<Utc>, count: usize) -> Vec<DateTime<Utc>> {
+    let mut upcoming = Vec::with_capacity(count);
+    let mut now = now;
+    while upcoming.len() < count {
+      match self.next_restart(now, started) {
+        Some(next) => { upcoming.push(next); now = next },
+        None => break
+      };
+    };
+
+    upcoming
+  }
+
+  /// The next occurrence of `times` or `cron` after `now`, evaluated on the wall clock of `now`'s timezone.
+  fn next_wall_clock<Z: TimeZone>(&self, now: DateTime<Z>) -> Option<DateTime<Utc>> {
+    let wall_clock = now.naive_local();
+    let times = match self.times.is_empty() {
+      true => None,
+      false => {
+        let mut times = self.times.clone();
+        times.sort();
+        // Any allowed day of the week comes around within 8 days, even across a repeated hour
+        let candidates = (0..=8)
+          .map(|days| wall_clock.date() + Duration::days(days))
+          .filter(|date| self.days.is_empty() || self.days.contains(&date.weekday()))
+          .flat_map(|date| times.iter().map(move |&time| date.and_time(time)));
+        first_after(&now, candidates)
+      }
+    };
+
+    // Cron expressions are evaluated on the wall clock as if it were UTC, which has no DST transitions,
+    // so that times in a gap or a repeated hour are handled the same as for `times`
+    let cron = self.cron.iter().filter_map(|cron| {
+      let candidates = cron.schedule.after(&Utc.from_utc_datetime(&wall_clock))
+        .map(|datetime| datetime.naive_utc());
+      first_after(&now, candidates)
+    });
+
+    times.into_iter().chain(cron).min()
+  }
+}
+
+impl Default for Schedule {
+  fn default() -> Self {
+    Schedule {
+      timezone: Timezone::Local,
+      times: vec![NaiveTime::from_hms(22, 0, 0)],
+      days: Vec::new(),
+      cron: Vec::new(),
+      max_uptime: None,
+      defer_until_empty: None
+    }
+  }
+}
+
+/// The first of `candidates` (given as wall clock times, in order) that falls strictly after `now`.
+fn first_after<Z: TimeZone>(now: &DateTime<Z>, candidates: impl Iterator<Item = NaiveDateTime>) -> Option<DateTime<Utc>> {
+  // Wall clock times that are not after `now` can only resolve to an instant before it
+  candidates
+    .skip_while(|&candidate| candidate <= now.naive_local())
+    .map(|candidate| resolve(&now.timezone(), candidate))
+    .find(|candidate| candidate > now)
+    .map(|candidate| candidate.with_timezone(&Utc))
+}
+
+/// Resolves a wall clock time to an instant in `tz`.
+/// Times skipped by a DST transition resolve to the first instant after the gap,
+/// and times repeated by a DST transition resolve to their earliest occurrence.
+fn resolve<Z: TimeZone>(tz: &Z, mut datetime: NaiveDateTime) -> DateTime<Z> {
+  loop {
+    match tz.from_local_datetime(&datetime) {
+      LocalResult::Single(datetime) => return datetime,
+      LocalResult::Ambiguous(earliest, _) => return earliest,
+      LocalResult::None => datetime = datetime.with_second(0).unwrap().with_nanosecond(0).unwrap() + Duration::minutes(1)
+    };
+  }
+}
+
+/// The timezone that restart times are given in, either an IANA name such as `Europe/Berlin`, or `local`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Timezone {
+  /// The timezone of the system puppetmaster is running on.
+  #[default]
+  Local,
+  /// A timezone from the IANA timezone database.
+  Named(Tz)
+}
+
+impl Timezone {
+  /// Formats an instant as a wall clock time in this timezone, with a `strftime`-style format string.
+  pub fn format(self, datetime: DateTime<Utc>, format: &str) -> String {
+    match self {
+      Timezone::Local => datetime.with_timezone(&Local).format(format).to_string(),
+      Timezone::Named(tz) => datetime.with_timezone(&tz).format(format).to_string()
+    }
+  }
+}
+
+impl FromStr for Timezone {
+  type Err = UnknownTimezone;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "local" => Ok(Timezone::Local),
+      _ => s.parse::<Tz>().map(Timezone::Named).map_err(|_| UnknownTimezone(s.to_owned()))
+    }
+  }
+}
+
+impl fmt::Display for Timezone {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Timezone::Local => f.write_str("local"),
+      Timezone::Named(tz) => f.write_str(tz.name())
+    }
+  }
+}
+
+impl TryFrom<String> for Timezone {
+  type Error = UnknownTimezone;
+
+  fn try_from(s: String) -> Result<Self, Self::Error> {
+    s.parse()
+  }
+}
+
+impl From<Timezone> for String {
+  fn from(timezone: Timezone) -> String {
+    timezone.to_string()
+  }
+}
+
+/// The error returned when parsing a `Timezone` from an unrecognized name.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("unknown timezone `{0}`, expected an IANA timezone name such as `Europe/Berlin`, or `local`")]
+pub struct UnknownTimezone(pub String);
+
+/// A parsed cron expression, which keeps its original text for display and serialization.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CronSchedule {
+  expression: String,
+  schedule: cron::Schedule
+}
+
+impl FromStr for CronSchedule {
+  type Err = InvalidCron;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    // The `cron` crate expects a leading seconds field, which standard cron expressions lack
+    let expression = s.trim();
+    let schedule = match expression.split_whitespace().collect::<Vec<&str>>()[..] {
+      [minute, hour, day, month, weekday] => {
+        format!("0 {} {} {} {} {}", minute, hour, day, month, translate_weekdays(weekday)).parse::<cron::Schedule>()
+      },
+      _ => expression.parse::<cron::Schedule>()
+    };
+
+    match schedule {
+      Ok(schedule) => Ok(CronSchedule { expression: expression.to_owned(), schedule }),
+      Err(err) => Err(InvalidCron(expression.to_owned(), err.to_string()))
+    }
+  }
+}
+
+impl fmt::Display for CronSchedule {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.expression)
+  }
+}
+
+impl TryFrom<String> for CronSchedule {
+  type Error = InvalidCron;
+
+  fn try_from(s: String) -> Result<Self, Self::Error> {
+    s.parse()
+  }
+}
+
+impl From<CronSchedule> for String {
+  fn from(cron: CronSchedule) -> String {
+    cron.expression
+  }
+}
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// Translates the weekday field of a standard cron expression, where days are numbered from 0 (or 7) for Sunday
+/// to 6 for Saturday, for the `cron` crate, where they are numbered from 1 for Sunday to 7 for Saturday.
+/// Elements with numbers in them are spelled out as a list of names, which mean the same in both.
+fn translate_weekdays(field: &str) -> String {
+  field.split(',')
+    .map(|element| translate_weekday_element(element).unwrap_or_else(|| element.to_owned()))
+    .collect::<Vec<String>>()
+    .join(",")
+}
+
+/// Translates one element of a weekday field, such as `1-5` or `0-6/2`,
+/// or returns `None` if it needs no translation or is invalid (in which case the `cron` crate rejects it).
+fn translate_weekday_element(element: &str) -> Option<String> {
+  let (range, step) = match element.split_once('/') {
+    Some((range, step)) => (range, step.parse::<usize>().ok().filter(|&step| step > 0)?),
+    None => (element, 1)
+  };
+
+  // `*` and names, along with steps over `*`, select the same days in both numberings
+  if !range.bytes().any(|b| b.is_ascii_digit()) { return None };
+
+  let day = |s: &str| s.parse::<usize>().ok().filter(|&n| n <= 7)
+    .or_else(|| WEEKDAYS.iter().position(|name| name.eq_ignore_ascii_case(s)));
+  let (first, last) = match range.split_once('-') {
+    Some((first, last)) => (day(first)?, day(last)?),
+    None => (day(range)?, day(range)?)
+  };
+
+  if first > last { return None };
+  let days = (first..=last).step_by(step)
+    .map(|n| WEEKDAYS[n % 7])
+    .collect::<Vec<&str>>();
+  Some(days.join(","))
+}
+
+/// The error returned when parsing a `CronSchedule` from an invalid expression.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid cron expression `{0}`: {1}")]
+pub struct InvalidCron(pub String, pub String);
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn berlin(times: &[(u32, u32)]) -> Schedule {
+    Schedule {
+      timezone: "Europe/Berlin".parse().unwrap(),
+      times: times.iter().map(|&(hour, minute)| NaiveTime::from_hms(hour, minute, 0)).collect(),
+      ..Schedule::default()
+    }
+  }
+
+  fn utc(s: &str) -> DateTime<Utc> {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn next_restart_in_timezone() {
+    let schedule = berlin(&[(4, 0)]);
+    let now = utc("2021-06-01T12:00:00Z");
+    assert_eq!(schedule.next_restart(now, now), Some(utc("2021-06-02T02:00:00Z")));
+  }
+
+  #[test]
+  fn time_in_dst_gap_resolves_to_end_of_gap() {
+    // Clocks in Berlin went from 02:00 straight to 03:00 on 2021-03-28
+    let schedule = berlin(&[(2, 30)]);
+    let now = utc("2021-03-27T12:00:00Z");
+    assert_eq!(schedule.upcoming(now, now, 2), [utc("2021-03-28T01:00:00Z"), utc("2021-03-29T00:30:00Z")]);
+  }
+
+  #[test]
+  fn repeated_time_restarts_once() {
+    // Clocks in Berlin went from 03:00 back to 02:00 on 2021-10-31, so 02:30 happened twice
+    let schedule = berlin(&[(2, 30)]);
+    let now = utc("2021-10-30T12:00:00Z");
+    assert_eq!(schedule.upcoming(now, now, 2), [utc("2021-10-31T00:30:00Z"), utc("2021-11-01T01:30:00Z")]);
+
+    // Between the two occurrences, the second one is skipped as well
+    let now = utc("2021-10-31T01:00:00Z");
+    assert_eq!(schedule.next_restart(now, now), Some(utc("2021-11-01T01:30:00Z")));
+  }
+
+  #[test]
+  fn days_of_week() {
+    let schedule = Schedule { days: vec![Weekday::Mon, Weekday::Thu], ..berlin(&[(4, 0)]) };
+    // A Tuesday
+    let now = utc("2021-06-01T12:00:00Z");
+    assert_eq!(schedule.upcoming(now, now, 2), [utc("2021-06-03T02:00:00Z"), utc("2021-06-07T02:00:00Z")]);
+  }
+
+  fn weekdays(expression: &str) -> Vec<String> {
+    let cron = expression.parse::<CronSchedule>().unwrap();
+    cron.schedule.after(&utc("2021-06-01T00:00:00Z"))
+      .take(7)
+      .map(|datetime| datetime.format("%a").to_string())
+      .collect()
+  }
+
+  #[test]
+  fn standard_cron_weekdays() {
+    assert_eq!(weekdays("0 4 * * 1"), ["Mon"; 7]);
+    assert_eq!(weekdays("0 4 * * 0"), ["Sun"; 7]);
+    assert_eq!(weekdays("0 4 * * 7"), ["Sun"; 7]);
+    assert_eq!(weekdays("0 4 * * 1-5"), ["Tue", "Wed", "Thu", "Fri", "Mon", "Tue", "Wed"]);
+    assert_eq!(weekdays("0 4 * * 5-7"), ["Fri", "Sat", "Sun", "Fri", "Sat", "Sun", "Fri"]);
+    assert_eq!(weekdays("0 4 * * 0-6/3"), ["Wed", "Sat", "Sun", "Wed", "Sat", "Sun", "Wed"]);
+    assert_eq!(weekdays("0 4 * * */2"), ["Tue", "Thu", "Sat", "Sun", "Tue", "Thu", "Sat"]);
+    assert_eq!(weekdays("0 4 * * 6,0"), ["Sat", "Sun", "Sat", "Sun", "Sat", "Sun", "Sat"]);
+    assert_eq!(weekdays("0 4 * * Mon-Fri"), ["Tue", "Wed", "Thu", "Fri", "Mon", "Tue", "Wed"]);
+  }
+
+  #[test]
+  fn cron_with_seconds_keeps_crate_weekdays() {
+    assert_eq!(weekdays("0 0 4 * * 1"), ["Sun"; 7]);
+    assert_eq!(weekdays("0 0 4 * * Mon"), ["Mon"; 7]);
+  }
+
+  #[test]
+  fn invalid_cron() {
+    assert!("0 4 * * 8".parse::<CronSchedule>().is_err());
+    assert!("0 4 * * 5-1".parse::<CronSchedule>().is_err());
+    assert!("0 4 * *".parse::<CronSchedule>().is_err());
+  }
+
+  #[test]
+  fn max_uptime() {
+    let schedule = Schedule { max_uptime: Some(std::time::Duration::from_secs(6 * 3600)), ..berlin(&[(4, 0)]) };
+    let started = utc("2021-06-01T12:00:00Z");
+    let now = utc("2021-06-01T13:00:00Z");
+    assert_eq!(schedule.upcoming(now, started, 2), [utc("2021-06-01T18:00:00Z"), utc("2021-06-02T02:00:00Z")]);
+  }
+
+  #[test]
+  fn max_uptime_too_far_away() {
+    let schedule = Schedule { max_uptime: Some(humantime::parse_duration("1000000y").unwrap()), ..berlin(&[(4, 0)]) };
+    let now = utc("2021-06-01T12:00:00Z");
+    assert_eq!(schedule.next_restart(now, now), Some(utc("2021-06-02T02:00:00Z")));
+  }
+}
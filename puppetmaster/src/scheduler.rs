@@ -0,0 +1,283 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use puppet::{EventHandler, Puppet};
+use tokio::sync::watch;
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::schedule::Schedule;
+use crate::warning::format_remaining;
+
+/// The restart that the server is currently counting down to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlannedRestart {
+  /// When the server will be restarted, or `None` if no restart is planned.
+  pub at: Option<DateTime<Utc>>,
+  /// What caused the restart to be planned for this time.
+  pub change: Change
+}
+
+/// How the planned restart came to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+  /// Taken from the schedule as the server started.
+  Scheduled,
+  /// Moved to a specific time on request, such as `restart in 10m`.
+  Moved,
+  /// Pushed back on request, such as `postpone 30m`.
+  Postponed,
+  /// The previously planned restart was skipped on request, so the next one from the schedule applies.
+  Cancelled
+}
+
+/// Why the planned restart could not be changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum ScheduleError {
+  #[error("No restart is planned")]
+  NothingPlanned,
+  #[error("That is too far in the future")]
+  TooFarAway
+}
+
+/// Plans the server's restarts from a `Schedule`, allowing the upcoming restart to be moved around
+/// while the server is running. Changes can be watched with `Scheduler::subscribe`.
+#[derive(Debug)]
+pub struct Scheduler {
+  schedule: Schedule,
+  /// When the server was last started, which `schedule.max-uptime` counts from.
+  started: Mutex<DateTime<Utc>>,
+  planned: watch::Sender<PlannedRestart>
+}
+
+impl Scheduler {
+  pub fn new(schedule: Schedule) -> Self {
+    let now = Utc::now();
+    let planned = PlannedRestart { at: schedule.next_restart(now, now), change: Change::Scheduled };
+    Scheduler { schedule, started: Mutex::new(now), planned: watch::Sender::new(planned) }
+  }
+
+  /// Plans the next restart from the schedule, for a server that has just been started.
+  pub fn reset(&self, started: DateTime<Utc>) {
+    *self.started.lock().unwrap() = started;
+    let at = self.schedule.next_restart(started, started);
+    self.planned.send_replace(PlannedRestart { at, change: Change::Scheduled });
+  }
+
+  /// The restart that is currently planned.
+  pub fn planned(&self) -> PlannedRestart {
+    *self.planned.borrow()
+  }
+
+  /// Watch for changes to the planned restart.
+  pub fn subscribe(&self) -> watch::Receiver<PlannedRestart> {
+    self.planned.subscribe()
+  }
+
+  /// Restart the server at `at` instead of the currently planned time.
+  pub fn restart_at(&self, at: DateTime<Utc>) {
+    self.planned.send_replace(PlannedRestart { at: Some(at), change: Change::Moved });
+  }
+
+  /// Restart the server `delay` from now instead of the currently planned time.
+  pub fn restart_in(&self, delay: Duration) -> Result<(), ScheduleError> {
+    let at = after(Utc::now(), delay).ok_or(ScheduleError::TooFarAway)?;
+    self.restart_at(at);
+    Ok(())
+  }
+
+  /// Restart the server `delay` from now, unless a restart is already planned sooner than that.
+  /// A delay too long to plan a restart for is never sooner, so it leaves the planned restart as it is.
+  pub fn restart_within(&self, delay: Duration) {
+    let at = match after(Utc::now(), delay) {
+      Some(at) => at,
+      None => return
+    };
+
+    self.planned.send_if_modified(|planned| match planned.at {
+      Some(planned_at) if planned_at <= at => false,
+      _ => {
+        *planned = PlannedRestart { at: Some(at), change: Change::Moved };
+        true
+      }
+    });
+  }
+
+  /// Restart the server right away, without any warnings.
+  pub fn restart_now(&self) {
+    self.restart_at(Utc::now());
+  }
+
+  /// Push the planned restart back by `delay`.
+  pub fn postpone(&self, delay: Duration) -> Result<(), ScheduleError> {
+    let mut result = Err(ScheduleError::NothingPlanned);
+    self.planned.send_if_modified(|planned| {
+      let at = match planned.at {
+        Some(at) => after(at.max(Utc::now()), delay),
+        None => return false
+      };
+
+      result = at.map(|at| *planned = PlannedRestart { at: Some(at), change: Change::Postponed })
+        .ok_or(ScheduleError::TooFarAway);
+      result.is_ok()
+    });
+
+    result
+  }
+
+  /// Skip the planned restart, so that the next one from the schedule after it applies instead.
+  pub fn cancel(&self) -> Result<(), ScheduleError> {
+    let started = *self.started.lock().unwrap();
+    let cancelled = self.planned.send_if_modified(|planned| match planned.at {
+      Some(at) => {
+        let at = self.schedule.next_restart(at.max(Utc::now()), started);
+        *planned = PlannedRestart { at, change: Change::Cancelled };
+        true
+      },
+      None => false
+    });
+
+    cancelled.then_some(()).ok_or(ScheduleError::NothingPlanned)
+  }
+}
+
+/// `delay` after `from`, or `None` if that is too far in the future to represent.
+fn after(from: DateTime<Utc>, delay: Duration) -> Option<DateTime<Utc>> {
+  from.checked_add_signed(chrono::Duration::from_std(delay).ok()?)
+}
+
+/// Handles the wrapper commands that control the scheduler, such as `!pm restart in 10m`.
+#[derive(Debug)]
+pub struct SchedulerCommands<'a> {
+  scheduler: &'a Scheduler
+}
+
+impl<'a> SchedulerCommands<'a> {
+  pub fn new(scheduler: &'a Scheduler) -> Self {
+    SchedulerCommands { scheduler }
+  }
+}
+
+#[async_trait]
+impl EventHandler for SchedulerCommands<'_> {
+  async fn wrapper_command(&self, _puppet: &Puppet, command: &str) {
+    let words = command.split_whitespace().collect::<Vec<&str>>();
+    match words.as_slice() {
+      ["restart", "now"] => self.scheduler.restart_now(),
+      ["restart", "in", delay @ ..] => match humantime::parse_duration(&delay.join(" ")) {
+        Ok(delay) => if let Err(err) = self.scheduler.restart_in(delay) {
+          println!("[Puppetmaster] {}", err);
+        },
+        Err(err) => println!("[Puppetmaster] Invalid duration: {}", err)
+      },
+      ["postpone", delay @ ..] => match humantime::parse_duration(&delay.join(" ")) {
+        Ok(delay) => if let Err(err) = self.scheduler.postpone(delay) {
+          println!("[Puppetmaster] {}", err);
+        },
+        Err(err) => println!("[Puppetmaster] Invalid duration: {}", err)
+      },
+      ["cancel"] => if let Err(err) = self.scheduler.cancel() {
+        println!("[Puppetmaster] {}", err);
+      },
+      ["status"] => match self.scheduler.planned().at {
+        Some(at) => {
+          let remaining = (at - Utc::now()).to_std().unwrap_or_default();
+          println!("[Puppetmaster] Restarting in {}", format_remaining(remaining));
+        },
+        None => println!("[Puppetmaster] No restart is planned")
+      },
+      _ => {
+        println!("[Puppetmaster] Unknown command `{}`, expected one of:", command);
+        println!("[Puppetmaster]   restart now | restart in <duration> | postpone <duration> | cancel | status");
+      }
+    };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::schedule::Timezone;
+
+  const MINUTE: Duration = Duration::from_secs(60);
+  const HOUR: Duration = Duration::from_secs(60 * 60);
+
+  /// A scheduler for a server restarted every hour on the hour.
+  fn hourly() -> Scheduler {
+    let cron = "0 * * * *".parse().unwrap();
+    Scheduler::new(Schedule { timezone: Timezone::Named(chrono_tz::UTC), times: Vec::new(), cron: vec![cron], ..Schedule::default() })
+  }
+
+  fn unscheduled() -> Scheduler {
+    Scheduler::new(Schedule { times: Vec::new(), ..Schedule::default() })
+  }
+
+  /// How far in the future the planned restart is, give or take the time the test has taken so far.
+  fn planned_in(scheduler: &Scheduler) -> Option<Duration> {
+    scheduler.planned().at.map(|at| (at - Utc::now()).to_std().unwrap_or_default())
+  }
+
+  fn roughly(duration: Option<Duration>, expected: Duration) -> bool {
+    duration.is_some_and(|duration| duration <= expected && duration + Duration::from_secs(5) > expected)
+  }
+
+  #[test]
+  fn restart_in() {
+    let scheduler = unscheduled();
+    assert_eq!(scheduler.planned().at, None);
+    scheduler.restart_in(10 * MINUTE).unwrap();
+    assert!(roughly(planned_in(&scheduler), 10 * MINUTE));
+    assert_eq!(scheduler.planned().change, Change::Moved);
+  }
+
+  #[test]
+  fn restart_too_far_away() {
+    let scheduler = hourly();
+    let planned = scheduler.planned();
+    assert_eq!(scheduler.restart_in(humantime::parse_duration("1000000y").unwrap()), Err(ScheduleError::TooFarAway));
+    assert_eq!(scheduler.restart_in(Duration::MAX), Err(ScheduleError::TooFarAway));
+    assert_eq!(scheduler.postpone(Duration::MAX), Err(ScheduleError::TooFarAway));
+    scheduler.restart_within(Duration::MAX);
+    assert_eq!(scheduler.planned(), planned);
+  }
+
+  #[test]
+  fn restart_within_keeps_sooner_restarts() {
+    let scheduler = unscheduled();
+    scheduler.restart_within(HOUR);
+    assert!(roughly(planned_in(&scheduler), HOUR));
+    scheduler.restart_within(2 * HOUR);
+    assert!(roughly(planned_in(&scheduler), HOUR));
+    scheduler.restart_within(MINUTE);
+    assert!(roughly(planned_in(&scheduler), MINUTE));
+  }
+
+  #[test]
+  fn postpone() {
+    let scheduler = unscheduled();
+    assert_eq!(scheduler.postpone(HOUR), Err(ScheduleError::NothingPlanned));
+    scheduler.restart_in(10 * MINUTE).unwrap();
+    scheduler.postpone(HOUR).unwrap();
+    assert!(roughly(planned_in(&scheduler), HOUR + 10 * MINUTE));
+    assert_eq!(scheduler.planned().change, Change::Postponed);
+  }
+
+  #[test]
+  fn cancel_skips_to_the_next_scheduled_restart() {
+    let scheduler = hourly();
+    let next = scheduler.planned().at.unwrap();
+    scheduler.cancel().unwrap();
+    assert_eq!(scheduler.planned(), PlannedRestart { at: Some(next + chrono::Duration::hours(1)), change: Change::Cancelled });
+
+    // A restart that was moved is replaced by the next one from the schedule after it
+    scheduler.restart_in(3 * HOUR).unwrap();
+    let moved = scheduler.planned().at.unwrap();
+    scheduler.cancel().unwrap();
+    assert!(scheduler.planned().at.is_some_and(|at| at > moved && at - moved <= chrono::Duration::hours(1)));
+  }
+
+  #[test]
+  fn cancel_without_restart() {
+    assert_eq!(unscheduled().cancel(), Err(ScheduleError::NothingPlanned));
+  }
+}
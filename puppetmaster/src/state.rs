@@ -0,0 +1,83 @@
+use async_trait::async_trait;
+use puppet::{ConsoleLine, EventHandler, Puppet};
+use tokio::sync::watch;
+
+use std::collections::BTreeSet;
+
+use crate::util::AtomicFlag;
+
+/// Tracks what has happened during a single run of the server, from its events.
+#[derive(Debug)]
+pub struct ServerState {
+  /// Whether the server announced that it was shutting down.
+  pub stopping: AtomicFlag,
+  /// The startup time in seconds reported by the server, once it has finished starting up.
+  startup_time: watch::Sender<Option<f64>>,
+  /// The usernames of the players currently online.
+  players: watch::Sender<BTreeSet<String>>
+}
+
+impl ServerState {
+  /// Waits for the server to finish starting up, returning its reported startup time in seconds.
+  pub async fn ready(&self) -> f64 {
+    let mut receiver = self.startup_time.subscribe();
+    let startup_time = *receiver.wait_for(Option::is_some).await
+      .expect("sender is held by self");
+    startup_time.unwrap()
+  }
+
+  /// The number of players currently online.
+  pub fn player_count(&self) -> usize {
+    self.players.borrow().len()
+  }
+
+  /// Whether the player with this username is currently online.
+  pub fn is_online(&self, username: &str) -> bool {
+    self.players.borrow().contains(username)
+  }
+
+  pub fn player_joined(&self, username: &str) {
+    self.players.send_modify(|players| { players.insert(username.to_owned()); });
+  }
+
+  pub fn player_left(&self, username: &str) {
+    self.players.send_modify(|players| { players.remove(username); });
+  }
+
+  /// Waits for there to be no players online.
+  pub async fn empty(&self) {
+    let mut receiver = self.players.subscribe();
+    receiver.wait_for(BTreeSet::is_empty).await
+      .expect("sender is held by self");
+  }
+}
+
+impl Default for ServerState {
+  fn default() -> Self {
+    ServerState {
+      stopping: AtomicFlag::new(),
+      startup_time: watch::Sender::new(None),
+      players: watch::Sender::new(BTreeSet::new())
+    }
+  }
+}
+
+#[async_trait]
+impl EventHandler for ServerState {
+  async fn console_event(&self, _puppet: &Puppet, line: &ConsoleLine) {
+    match line {
+      ConsoleLine::PlayerJoined { username } => self.player_joined(username),
+      ConsoleLine::PlayerLeft { username } => self.player_left(username),
+      _ => ()
+    };
+  }
+
+  async fn server_ready(&self, _puppet: &Puppet, time: f64) {
+    println!("[Puppetmaster] Server started in {:.3} seconds", time);
+    self.startup_time.send_replace(Some(time));
+  }
+
+  async fn server_stopping(&self, _puppet: &Puppet) {
+    self.stopping.set();
+  }
+}
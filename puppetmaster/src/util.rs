@@ -1,5 +1,12 @@
+use tokio::time::Instant;
+
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::config::Config;
 
+#[derive(Debug, Default)]
 pub struct AtomicFlag(AtomicBool);
 
 impl AtomicFlag {
@@ -16,3 +23,91 @@ impl AtomicFlag {
     self.0.load(Ordering::Relaxed)
   }
 }
+
+/// Tracks how long a condition has held for, such as a limit being exceeded.
+#[derive(Debug, Default)]
+pub struct Sustained {
+  since: Option<Instant>,
+  /// Whether this has already been acted on, which is reset once the condition stops holding.
+  fired: bool
+}
+
+impl Sustained {
+  /// Returns `true` once the condition has held for `duration`,
+  /// and not again until it has stopped holding and held for that long once more.
+  pub fn update(&mut self, holds: bool, now: Instant, duration: Duration) -> bool {
+    if !holds {
+      *self = Sustained::default();
+      return false;
+    };
+
+    let since = *self.since.get_or_insert(now);
+    if !self.fired && now - since >= duration {
+      self.fired = true;
+      return true;
+    };
+
+    false
+  }
+}
+
+/// A JDK tool such as `jstack` from the same JDK as the configured `java`, or from `JAVA_HOME`, or otherwise from the `PATH`.
+pub fn jdk_tool(config: &Config, name: &str) -> PathBuf {
+  let bin_dir = match &config.java {
+    Some(java) => java.parent().map(Path::to_owned),
+    None => std::env::var_os("JAVA_HOME").map(|java_home| Path::new(&java_home).join("bin"))
+  };
+
+  match bin_dir {
+    Some(bin_dir) if bin_dir.join(name).exists() => bin_dir.join(name),
+    _ => PathBuf::from(name)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MINUTE: Duration = Duration::from_secs(60);
+
+  #[test]
+  fn fires_once_held_for_duration() {
+    let start = Instant::now();
+    let mut sustained = Sustained::default();
+    assert!(!sustained.update(true, start, 2 * MINUTE));
+    assert!(!sustained.update(true, start + MINUTE, 2 * MINUTE));
+    assert!(sustained.update(true, start + 2 * MINUTE, 2 * MINUTE));
+  }
+
+  #[test]
+  fn fires_only_once_while_held() {
+    let start = Instant::now();
+    let mut sustained = Sustained::default();
+    assert!(sustained.update(true, start, Duration::ZERO));
+    assert!(!sustained.update(true, start + MINUTE, Duration::ZERO));
+    assert!(!sustained.update(true, start + 10 * MINUTE, Duration::ZERO));
+  }
+
+  #[test]
+  fn resets_when_no_longer_held() {
+    let start = Instant::now();
+    let mut sustained = Sustained::default();
+    assert!(!sustained.update(true, start, 2 * MINUTE));
+    assert!(!sustained.update(false, start + MINUTE, 2 * MINUTE));
+    // The condition has to hold for the whole duration again after a break
+    assert!(!sustained.update(true, start + 2 * MINUTE, 2 * MINUTE));
+    assert!(!sustained.update(true, start + 3 * MINUTE, 2 * MINUTE));
+    assert!(sustained.update(true, start + 4 * MINUTE, 2 * MINUTE));
+  }
+
+  #[test]
+  fn fires_again_after_a_break() {
+    let start = Instant::now();
+    let mut sustained = Sustained::default();
+    assert!(!sustained.update(true, start, MINUTE));
+    assert!(sustained.update(true, start + MINUTE, MINUTE));
+    assert!(!sustained.update(false, start + 3 * MINUTE, MINUTE));
+    assert!(!sustained.update(true, start + 4 * MINUTE, MINUTE));
+    assert!(sustained.update(true, start + 5 * MINUTE, MINUTE));
+  }
+}
@@ -0,0 +1,303 @@
+use async_trait::async_trait;
+use puppet::{Color, ConsoleLine, EventHandler, Puppet, Text};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::scheduler::Scheduler;
+use crate::state::ServerState;
+use crate::warning::format_remaining;
+use crate::Error;
+
+/// Lets players restart the server by voting for it in chat.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct VoteConfig {
+  pub enabled: bool,
+  /// The chat message that casts a vote.
+  pub command: String,
+  /// The fraction of online players that must vote for the restart, such as `0.5` for half of them.
+  pub threshold: f64,
+  /// How long a vote stays open after it was started, such as `"2m"`.
+  #[serde(with = "humantime_serde")]
+  pub expiry: Duration,
+  /// How long after a vote ends before another can be started.
+  #[serde(with = "humantime_serde")]
+  pub cooldown: Duration,
+  /// How long the server counts down before restarting once a vote passes.
+  #[serde(with = "humantime_serde")]
+  pub countdown: Duration
+}
+
+impl Default for VoteConfig {
+  fn default() -> Self {
+    VoteConfig {
+      enabled: false,
+      command: "!voterestart".to_owned(),
+      threshold: 0.5,
+      expiry: Duration::from_secs(2 * 60),
+      cooldown: Duration::from_secs(30 * 60),
+      countdown: Duration::from_secs(30)
+    }
+  }
+}
+
+impl VoteConfig {
+  /// Checks that `threshold` is a fraction of the online players that a vote can reach.
+  pub fn validate(&self) -> Result<(), Error> {
+    match self.threshold > 0.0 && self.threshold <= 1.0 {
+      true => Ok(()),
+      false => Err(Error::InvalidVoteThreshold(self.threshold))
+    }
+  }
+}
+
+/// The state of voting, kept across restarts of the server so that the cooldown carries over.
+#[derive(Debug)]
+pub struct Votes<'a> {
+  config: &'a VoteConfig,
+  state: Mutex<VoteState>,
+  /// When the current vote expires, if there is one.
+  expires: watch::Sender<Option<Instant>>
+}
+
+#[derive(Debug, Default)]
+struct VoteState {
+  /// The players who have voted in the current vote.
+  voters: BTreeSet<String>,
+  /// When the current vote was started, if there is one.
+  started: Option<Instant>,
+  /// When the last vote ended.
+  ended: Option<Instant>
+}
+
+/// What happened to a vote that was cast.
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+  Cooldown(Duration),
+  AlreadyVoted,
+  Counted { votes: usize, needed: usize, started: bool },
+  Passed
+}
+
+impl<'a> Votes<'a> {
+  pub fn new(config: &'a VoteConfig) -> Self {
+    Votes { config, state: Mutex::new(VoteState::default()), expires: watch::Sender::new(None) }
+  }
+
+  /// Returns a handler for a single run of the server, which counts votes from its players.
+  pub fn handler(&'a self, state: &'a ServerState, scheduler: &'a Scheduler) -> Option<VoteHandler<'a>> {
+    self.config.enabled.then_some(VoteHandler { votes: self, state, scheduler })
+  }
+
+  fn cast(&self, username: &str, state: &ServerState, now: Instant) -> Outcome {
+    let mut vote = self.state.lock().unwrap();
+    self.expire(&mut vote, now);
+    if vote.started.is_none() {
+      if let Some(ended) = vote.ended.filter(|&ended| now < ended + self.config.cooldown) {
+        return Outcome::Cooldown(ended + self.config.cooldown - now);
+      };
+
+      vote.started = Some(now);
+      self.expires.send_replace(Some(now + self.config.expiry));
+    };
+
+    let started = vote.voters.is_empty();
+    if !vote.voters.insert(username.to_owned()) {
+      return Outcome::AlreadyVoted;
+    };
+
+    // Players who voted and then left no longer count
+    let votes = vote.voters.iter().filter(|voter| state.is_online(voter)).count();
+    let needed = ((state.player_count() as f64 * self.config.threshold).ceil() as usize).max(1);
+    if votes >= needed {
+      vote.voters.clear();
+      vote.started = None;
+      vote.ended = Some(now);
+      self.expires.send_replace(None);
+      Outcome::Passed
+    } else {
+      Outcome::Counted { votes, needed, started }
+    }
+  }
+
+  /// Ends the current vote if it has expired by `now`, returning whether it did.
+  fn expire(&self, vote: &mut VoteState, now: Instant) -> bool {
+    match vote.started.map(|started| started + self.config.expiry).filter(|&expired| now >= expired) {
+      Some(expired) => {
+        vote.voters.clear();
+        vote.started = None;
+        vote.ended = Some(expired);
+        self.expires.send_replace(None);
+        true
+      },
+      None => false
+    }
+  }
+
+  /// Sleeps until the running restart vote's deadline, telling players if it is still open by then,
+  /// which expires it and starts `vote.cooldown`.
+  pub async fn watch(&self, puppet: &Puppet) -> Result<Infallible, Error> {
+    let mut expires = self.expires.subscribe();
+    loop {
+      let expiry = *expires.borrow_and_update();
+      let expiry = match expiry {
+        Some(expiry) => expiry,
+        None => {
+          expires.changed().await.expect("sender is held by self");
+          continue;
+        }
+      };
+
+      tokio::select!{
+        () = tokio::time::sleep_until(expiry) => {
+          // A vote that was cast in the meantime may have already ended it
+          if self.expire(&mut self.state.lock().unwrap(), Instant::now()) {
+            println!("[Puppetmaster] Restart vote expired");
+            puppet.tellraw("@a", &Text::new("The vote to restart the server has expired").color(Color::Red)).await?;
+          };
+        },
+        result = expires.changed() => result.expect("sender is held by self")
+      };
+    }
+  }
+}
+
+/// Counts votes cast in chat during a single run of the server, created by `Votes::handler`.
+#[derive(Debug)]
+pub struct VoteHandler<'a> {
+  votes: &'a Votes<'a>,
+  state: &'a ServerState,
+  scheduler: &'a Scheduler
+}
+
+#[async_trait]
+impl EventHandler for VoteHandler<'_> {
+  async fn console_event(&self, puppet: &Puppet, line: &ConsoleLine) {
+    let config = self.votes.config;
+    let username = match line {
+      ConsoleLine::ChatMessage { username, message } if message.trim().eq_ignore_ascii_case(&config.command) => username,
+      _ => return
+    };
+
+    let result = match self.votes.cast(username, self.state, Instant::now()) {
+      Outcome::Cooldown(remaining) => {
+        let message = format!("Another restart vote can be started in {}", format_remaining(remaining));
+        puppet.tellraw(username, &Text::new(message).color(Color::Red)).await
+      },
+      Outcome::AlreadyVoted => {
+        puppet.tellraw(username, &Text::new("You have already voted to restart").color(Color::Red)).await
+      },
+      Outcome::Counted { votes, needed, started } => {
+        let message = match started {
+          true => format!("{} voted to restart the server, type {} within {} to vote ({}/{})",
+            username, config.command, format_remaining(config.expiry), votes, needed),
+          false => format!("{} voted to restart the server ({}/{})", username, votes, needed)
+        };
+        puppet.tellraw("@a", &Text::new(message).color(Color::Yellow)).await
+      },
+      Outcome::Passed => {
+        println!("[Puppetmaster] Restart vote passed");
+        // A restart that is already planned sooner is left as it is
+        self.scheduler.restart_within(config.countdown);
+        puppet.tellraw("@a", &Text::new("The vote to restart the server has passed").color(Color::Gold)).await
+      }
+    };
+
+    if let Err(err) = result {
+      println!("[Puppetmaster] Failed to announce restart vote: {}", err);
+    };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SECOND: Duration = Duration::from_secs(1);
+
+  fn online(usernames: &[&str]) -> ServerState {
+    let state = ServerState::default();
+    for username in usernames {
+      state.player_joined(username);
+    };
+
+    state
+  }
+
+  #[test]
+  fn passes_at_threshold() {
+    let config = VoteConfig { enabled: true, ..VoteConfig::default() };
+    let votes = Votes::new(&config);
+    let state = online(&["Notch", "jeb_", "Dinnerbone"]);
+    let now = Instant::now();
+    assert_eq!(votes.cast("Notch", &state, now), Outcome::Counted { votes: 1, needed: 2, started: true });
+    assert_eq!(votes.cast("jeb_", &state, now + SECOND), Outcome::Passed);
+  }
+
+  #[test]
+  fn unanimous_threshold() {
+    let config = VoteConfig { enabled: true, threshold: 1.0, ..VoteConfig::default() };
+    let votes = Votes::new(&config);
+    let state = online(&["Notch", "jeb_", "Dinnerbone"]);
+    let now = Instant::now();
+    assert_eq!(votes.cast("Notch", &state, now), Outcome::Counted { votes: 1, needed: 3, started: true });
+    assert_eq!(votes.cast("jeb_", &state, now), Outcome::Counted { votes: 2, needed: 3, started: false });
+    assert_eq!(votes.cast("Dinnerbone", &state, now), Outcome::Passed);
+  }
+
+  #[test]
+  fn one_vote_per_player() {
+    let config = VoteConfig { enabled: true, threshold: 1.0, ..VoteConfig::default() };
+    let votes = Votes::new(&config);
+    let state = online(&["Notch", "jeb_"]);
+    let now = Instant::now();
+    assert_eq!(votes.cast("Notch", &state, now), Outcome::Counted { votes: 1, needed: 2, started: true });
+    assert_eq!(votes.cast("Notch", &state, now + SECOND), Outcome::AlreadyVoted);
+  }
+
+  #[test]
+  fn players_who_left_do_not_count() {
+    let config = VoteConfig { enabled: true, threshold: 1.0, ..VoteConfig::default() };
+    let votes = Votes::new(&config);
+    let state = online(&["Notch", "jeb_", "Dinnerbone"]);
+    let now = Instant::now();
+    votes.cast("Notch", &state, now);
+    state.player_left("Notch");
+    state.player_joined("Herobrine");
+    assert_eq!(votes.cast("jeb_", &state, now), Outcome::Counted { votes: 1, needed: 3, started: false });
+  }
+
+  #[test]
+  fn expired_vote_starts_cooldown() {
+    let config = VoteConfig { enabled: true, ..VoteConfig::default() };
+    let votes = Votes::new(&config);
+    let state = online(&["Notch", "jeb_", "Dinnerbone"]);
+    let now = Instant::now();
+    votes.cast("Notch", &state, now);
+    assert_eq!(*votes.expires.borrow(), Some(now + config.expiry));
+
+    // The cooldown counts from when the vote expired, not from when the late vote was cast
+    let late = now + config.expiry + SECOND;
+    assert_eq!(votes.cast("jeb_", &state, late), Outcome::Cooldown(config.cooldown - SECOND));
+    assert_eq!(*votes.expires.borrow(), None);
+
+    let after_cooldown = now + config.expiry + config.cooldown;
+    assert_eq!(votes.cast("jeb_", &state, after_cooldown), Outcome::Counted { votes: 1, needed: 2, started: true });
+  }
+
+  #[test]
+  fn validate_threshold() {
+    let threshold = |threshold| VoteConfig { threshold, ..VoteConfig::default() }.validate().is_ok();
+    assert!(threshold(0.5));
+    assert!(threshold(1.0));
+    assert!(!threshold(0.0));
+    assert!(!threshold(-0.5));
+    assert!(!threshold(1.5));
+    assert!(!threshold(f64::NAN));
+  }
+}
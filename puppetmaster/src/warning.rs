@@ -0,0 +1,213 @@
+use puppet::{Color, Puppet, Text, TitleSlot};
+
+use std::io;
+use std::time::Duration;
+
+/// The players that warnings are shown to.
+const TARGETS: &str = "@a";
+/// The ID of the bossbar counting down to a restart.
+const BOSSBAR_ID: &str = "puppetmaster:restart";
+/// Within this long of the restart, the countdown bossbar is updated every second rather than every `BOSSBAR_INTERVAL`.
+const BOSSBAR_FINAL: Duration = Duration::from_secs(60);
+const BOSSBAR_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A message sent to players some time before a scheduled restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Warning {
+  /// How long before the restart to send this warning, such as `"10m"` or `"30s"`.
+  #[serde(with = "humantime_serde")]
+  pub before: Duration,
+  /// The message to send, where `{remaining}` is replaced with the time left (such as `1 minute` or `30 seconds`),
+  /// `{seconds}` and `{minutes}` with the whole number of seconds or minutes left,
+  /// and `{time}` with the time of day of the restart.
+  #[serde(default = "default_message")]
+  pub message: String,
+  #[serde(default)]
+  pub delivery: Delivery,
+  /// The color of the message, for deliveries that show a text component.
+  pub color: Option<Color>
+}
+
+impl Warning {
+  pub fn new(before: Duration) -> Self {
+    Warning { before, message: default_message(), delivery: Delivery::default(), color: None }
+  }
+
+  /// Sends this warning to players, for a restart at time of day `time`.
+  /// Warnings delivered by bossbar are instead shown through a `Bossbar`.
+  pub async fn deliver(&self, puppet: &Puppet, time: &str) -> io::Result<()> {
+    let message = self.render(self.before, time);
+    match self.delivery {
+      Delivery::Say => puppet.command(format!("say {}", message)).await,
+      Delivery::Command => puppet.command(message).await,
+      Delivery::Tellraw => puppet.tellraw(TARGETS, &self.text(message)).await,
+      Delivery::Title => puppet.title(TARGETS, TitleSlot::Title, &self.text(message)).await,
+      Delivery::Subtitle => {
+        // A subtitle is only shown along with a title, so show an empty one
+        puppet.title(TARGETS, TitleSlot::Subtitle, &self.text(message)).await?;
+        puppet.title(TARGETS, TitleSlot::Title, &Text::new("")).await
+      },
+      Delivery::Actionbar => puppet.title(TARGETS, TitleSlot::Actionbar, &self.text(message)).await,
+      Delivery::Bossbar => Ok(())
+    }
+  }
+
+  fn render(&self, remaining: Duration, time: &str) -> String {
+    self.message
+      .replace("{remaining}", &format_remaining(remaining))
+      .replace("{seconds}", &remaining.as_secs().to_string())
+      .replace("{minutes}", &(remaining.as_secs() / 60).to_string())
+      .replace("{time}", time)
+  }
+
+  fn text(&self, message: String) -> Text {
+    match self.color {
+      Some(color) => Text::new(message).color(color),
+      None => Text::new(message)
+    }
+  }
+}
+
+/// How a warning is delivered to players.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Delivery {
+  /// Broadcast the message in chat with `say`.
+  #[default]
+  Say,
+  /// Run the message as a console command, such as `title @a title "Restarting"`.
+  Command,
+  /// Send the message in chat with `tellraw`, without the `[Server]` prefix.
+  Tellraw,
+  /// Show the message as a title in the middle of the screen.
+  Title,
+  /// Show the message as a subtitle in the middle of the screen.
+  Subtitle,
+  /// Show the message just above the hotbar.
+  Actionbar,
+  /// Show a bossbar counting down to the restart, starting at this warning and updated until the restart.
+  Bossbar
+}
+
+/// A bossbar counting down to a restart, shown from the first warning delivered by bossbar.
+#[derive(Debug)]
+pub struct Bossbar<'a> {
+  /// The latest warning delivered by bossbar, whose message is shown.
+  warning: &'a Warning
+}
+
+impl<'a> Bossbar<'a> {
+  /// Creates the bossbar for `warning`, replacing any left behind by a previous run of the server.
+  pub async fn create(puppet: &Puppet, warning: &'a Warning, time: &str) -> io::Result<Bossbar<'a>> {
+    let text = warning.text(warning.render(warning.before, time));
+    puppet.command(format!("bossbar remove {}", BOSSBAR_ID)).await?;
+    puppet.command(format!("bossbar add {} {}", BOSSBAR_ID, text)).await?;
+    puppet.command(format!("bossbar set {} color red", BOSSBAR_ID)).await?;
+    puppet.command(format!("bossbar set {} max {}", BOSSBAR_ID, warning.before.as_secs().max(1))).await?;
+    let mut bossbar = Bossbar { warning };
+    bossbar.update(puppet, warning.before, time).await?;
+    Ok(bossbar)
+  }
+
+  /// Shows `warning`'s message on the bossbar from now on.
+  pub async fn replace(&mut self, puppet: &Puppet, warning: &'a Warning, time: &str) -> io::Result<()> {
+    self.warning = warning;
+    self.update(puppet, warning.before, time).await
+  }
+
+  /// Updates the bossbar's message and progress to `remaining`, rounded to the nearest second.
+  pub async fn update(&mut self, puppet: &Puppet, remaining: Duration, time: &str) -> io::Result<()> {
+    let remaining = Duration::from_secs((remaining.as_millis() as u64 + 500) / 1000);
+    let text = self.warning.text(self.warning.render(remaining, time));
+    puppet.command(format!("bossbar set {} name {}", BOSSBAR_ID, text)).await?;
+    puppet.command(format!("bossbar set {} value {}", BOSSBAR_ID, remaining.as_secs())).await?;
+    // Players who joined since the last update should see it too
+    puppet.command(format!("bossbar set {} players {}", BOSSBAR_ID, TARGETS)).await
+  }
+
+  /// How long until the bossbar should next be updated, given how long is left until the restart.
+  /// Updates happen on whole seconds remaining, every `BOSSBAR_INTERVAL` and then every second within `BOSSBAR_FINAL`.
+  pub fn next_update(&self, remaining: Duration) -> Duration {
+    let remaining = remaining.as_millis() as u64;
+    let (interval, floor) = match remaining > BOSSBAR_FINAL.as_millis() as u64 {
+      true => (BOSSBAR_INTERVAL.as_millis() as u64, BOSSBAR_FINAL.as_millis() as u64),
+      false => (1000, 0)
+    };
+
+    let next = match remaining % interval {
+      0 => remaining.saturating_sub(interval),
+      offset => remaining - offset
+    };
+    Duration::from_millis(remaining - next.max(floor))
+  }
+
+  pub async fn remove(self, puppet: &Puppet) -> io::Result<()> {
+    puppet.command(format!("bossbar remove {}", BOSSBAR_ID)).await
+  }
+}
+
+/// The warnings sent when none are configured, counting down from 30 minutes to 1 second.
+pub fn default_warnings() -> Vec<Warning> {
+  [30 * 60, 10 * 60, 5 * 60, 60, 30, 10, 5, 4, 3, 2, 1].iter()
+    .map(|&secs| Warning::new(Duration::from_secs(secs)))
+    .collect()
+}
+
+fn default_message() -> String {
+  "{remaining} until server restart".to_owned()
+}
+
+/// Formats a duration in words rounded to the nearest second, such as `1 hour 30 minutes` or `10 seconds`.
+pub fn format_remaining(duration: Duration) -> String {
+  let secs = (duration.as_millis() as u64 + 500) / 1000;
+  let parts = [(secs / 3600, "hour"), (secs / 60 % 60, "minute"), (secs % 60, "second")];
+  let words = parts.iter()
+    .filter(|&&(n, _)| n > 0)
+    .map(|&(n, unit)| format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" }))
+    .collect::<Vec<String>>();
+  match words.is_empty() {
+    true => "0 seconds".to_owned(),
+    false => words.join(" ")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn secs(secs: f64) -> Duration {
+    Duration::from_secs_f64(secs)
+  }
+
+  #[test]
+  fn bossbar_updates_every_interval() {
+    let warning = Warning::new(Duration::from_secs(30 * 60));
+    let bossbar = Bossbar { warning: &warning };
+    assert_eq!(bossbar.next_update(secs(1800.0)), secs(10.0));
+    assert_eq!(bossbar.next_update(secs(125.0)), secs(5.0));
+    assert_eq!(bossbar.next_update(secs(120.0)), secs(10.0));
+    // The last update at the interval lands on the start of the final stretch
+    assert_eq!(bossbar.next_update(secs(62.0)), secs(2.0));
+    assert_eq!(bossbar.next_update(secs(61.5)), secs(1.5));
+  }
+
+  #[test]
+  fn bossbar_updates_every_second_at_the_end() {
+    let warning = Warning::new(Duration::from_secs(30 * 60));
+    let bossbar = Bossbar { warning: &warning };
+    assert_eq!(bossbar.next_update(secs(60.0)), secs(1.0));
+    assert_eq!(bossbar.next_update(secs(59.25)), secs(0.25));
+    assert_eq!(bossbar.next_update(secs(2.5)), secs(0.5));
+    assert_eq!(bossbar.next_update(secs(1.0)), secs(1.0));
+    assert_eq!(bossbar.next_update(Duration::ZERO), Duration::ZERO);
+  }
+
+  #[test]
+  fn format_remaining_rounds_to_seconds() {
+    assert_eq!(format_remaining(secs(0.4)), "0 seconds");
+    assert_eq!(format_remaining(secs(0.5)), "1 second");
+    assert_eq!(format_remaining(secs(90.0)), "1 minute 30 seconds");
+    assert_eq!(format_remaining(secs(3600.0 + 59.6)), "1 hour 1 minute");
+  }
+}
@@ -0,0 +1,211 @@
+use chrono::prelude::*;
+use console::style;
+use puppet::Puppet;
+use tokio::process::Command;
+
+use std::convert::Infallible;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::state::ServerState;
+use crate::util::{jdk_tool, AtomicFlag};
+use crate::warning::format_remaining;
+use crate::Error;
+
+/// The directory (relative to puppetmaster's working directory) that thread dumps are written to.
+const THREAD_DUMP_DIR: &str = "thread-dumps";
+const JSTACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Detecting a server that has stopped responding.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct WatchdogConfig {
+  /// How long the server may take to finish starting up before it is killed and treated as crashed,
+  /// such as `"10m"`, or `"0s"` to wait indefinitely. Servers started with `launch` are never killed for this,
+  /// as they may not log that they have started in a format puppetmaster recognises, and are instead
+  /// probed from then on.
+  #[serde(with = "humantime_serde")]
+  pub startup_timeout: Duration,
+  /// How often to check that a running server still responds to commands, or `"0s"` to never check.
+  #[serde(with = "humantime_serde")]
+  pub probe_interval: Duration,
+  /// How long to wait for the server to answer a probe.
+  #[serde(with = "humantime_serde")]
+  pub probe_timeout: Duration,
+  /// The command sent to the server as a probe, which should be cheap to run.
+  pub probe_command: String,
+  /// The start of the message the server is expected to answer a probe with, after the timestamp and thread.
+  /// Only messages logged by the server thread count, so that players cannot answer probes through chat.
+  /// Servers started with `launch` may log in any format, so a line containing this anywhere counts for them.
+  pub probe_response_prefix: String,
+  /// How many probes in a row may go unanswered before the server is restarted.
+  pub max_missed_probes: u32
+}
+
+impl Default for WatchdogConfig {
+  fn default() -> Self {
+    WatchdogConfig {
+      startup_timeout: Duration::from_secs(10 * 60),
+      probe_interval: Duration::from_secs(60),
+      probe_timeout: Duration::from_secs(10),
+      probe_command: "list".to_owned(),
+      probe_response_prefix: "There are ".to_owned(),
+      max_missed_probes: 3
+    }
+  }
+}
+
+/// Watches over a single run of the server, first over its startup with `startup_watchdog`,
+/// then over its responsiveness with `liveness_watchdog`.
+pub async fn watchdog(puppet: &Puppet, state: &ServerState, restart: &AtomicFlag, config: &Config) -> Result<Infallible, Error> {
+  if startup_watchdog(puppet, state, config).await? {
+    liveness_watchdog(puppet, state, restart, config).await?;
+  };
+
+  std::future::pending().await
+}
+
+/// Expects the server to finish starting up within `watchdog.startup-timeout` of being spawned,
+/// otherwise takes a thread dump and kills it, so that it is handled as a crash.
+/// Returns whether the server should be probed from now on.
+async fn startup_watchdog(puppet: &Puppet, state: &ServerState, config: &Config) -> Result<bool, Error> {
+  let timeout = config.watchdog.startup_timeout;
+  if timeout.is_zero() || tokio::time::timeout(timeout, state.ready()).await.is_ok() {
+    state.ready().await;
+    return Ok(true);
+  };
+
+  if config.launch.is_some() {
+    println!("[Puppetmaster] Server was not seen finishing starting up within {}, probing it from now on", format_remaining(timeout));
+    return Ok(true);
+  };
+
+  let message = format!("[Puppetmaster] Server did not finish starting up within {}, killing it", format_remaining(timeout));
+  println!("{}", style(message).red().bright());
+  thread_dump(puppet, config).await?;
+  puppet.kill().await?;
+  Ok(false)
+}
+
+/// Periodically sends `watchdog.probe-command` to the server, expecting a message starting with `watchdog.probe-response-prefix`.
+/// After `watchdog.max-missed-probes` consecutive probes go unanswered, takes a thread dump and restarts the server.
+async fn liveness_watchdog(puppet: &Puppet, state: &ServerState, restart: &AtomicFlag, config: &Config) -> Result<(), Error> {
+  let watchdog = &config.watchdog;
+  if watchdog.probe_interval.is_zero() { return Ok(()) };
+
+  let mut missed = 0;
+  let mut warned = false;
+  loop {
+    tokio::time::sleep(watchdog.probe_interval).await;
+    // A server that is shutting down is not expected to answer
+    if state.stopping.get() { return Ok(()) };
+
+    let mut unrecognised = None;
+    let answers = |line: &str| {
+      let answered = answers_probe(line, config);
+      if !answered && unrecognised.is_none() {
+        unrecognised = Some(line.to_owned());
+      };
+
+      answered
+    };
+
+    match puppet.query(&watchdog.probe_command, answers, watchdog.probe_timeout).await {
+      Ok(_) => missed = 0,
+      // The server is still printing to its console, the answer is just not in a format that was expected
+      Err(err) if err.kind() == io::ErrorKind::TimedOut && unrecognised.is_some() => {
+        missed = 0;
+        if !warned {
+          let message = format!("[Puppetmaster] Server's answer to the liveness probe was not recognised, \
+            check `watchdog.probe-response-prefix`: {}", unrecognised.unwrap());
+          println!("{}", style(message).yellow().bright());
+          warned = true;
+        };
+      },
+      Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+        missed += 1;
+        println!("[Puppetmaster] Server did not answer liveness probe ({}/{})", missed, watchdog.max_missed_probes);
+      },
+      // The server's console has closed, so it is already on its way out
+      Err(_) => return Ok(())
+    };
+
+    if missed >= watchdog.max_missed_probes.max(1) && !state.stopping.get() {
+      let message = format!("[Puppetmaster] Server stopped responding after {} missed liveness probes, restarting it", missed);
+      println!("{}", style(message).red().bright());
+      thread_dump(puppet, config).await?;
+      restart.set();
+      puppet.kill().await?;
+      return Ok(());
+    };
+  }
+}
+
+/// Whether `line` is the server's answer to a liveness probe.
+fn answers_probe(line: &str, config: &Config) -> bool {
+  let prefix = &config.watchdog.probe_response_prefix;
+  match &config.launch {
+    Some(_) => line.contains(prefix),
+    None => puppet::server_info_message(line).is_some_and(|message| message.starts_with(prefix))
+  }
+}
+
+/// Takes a thread dump of the server with `jstack`, writing it to the `thread-dumps` directory.
+/// Falls back to asking the JVM to print one to the console when `jstack` is unavailable.
+pub async fn thread_dump(puppet: &Puppet, config: &Config) -> Result<(), Error> {
+  let id = match puppet.id() {
+    Some(id) => id,
+    None => return Ok(())
+  };
+
+  let jstack = Command::new(jdk_tool(config, "jstack"))
+    .arg("-l").arg(id.to_string())
+    .kill_on_drop(true)
+    .output();
+  match tokio::time::timeout(JSTACK_TIMEOUT, jstack).await {
+    Ok(Ok(output)) if output.status.success() => {
+      let path = Path::new(THREAD_DUMP_DIR)
+        .join(format!("thread-dump-{}.txt", Local::now().format("%Y-%m-%d_%H-%M-%S")));
+      tokio::fs::create_dir_all(THREAD_DUMP_DIR).await?;
+      tokio::fs::write(&path, output.stdout).await?;
+      println!("[Puppetmaster] Thread dump written to {}", path.display());
+    },
+    _ => {
+      println!("[Puppetmaster] jstack failed, requesting a thread dump from the JVM instead");
+      puppet.request_thread_dump().await?;
+      // Give the JVM a moment to print it before anything else happens to the process
+      tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+  };
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::LaunchConfig;
+
+  #[test]
+  fn probe_answers() {
+    let config = Config::default();
+    assert!(answers_probe("[12:00:00] [Server thread/INFO]: There are 0 of a max of 20 players online: ", &config));
+    assert!(answers_probe("[12:00:00 INFO]: There are 3 of a max of 20 players online: Notch, jeb_, Dinnerbone", &config));
+    // Players cannot answer probes through chat
+    assert!(!answers_probe("[12:00:00] [Async Chat Thread - #0/INFO]: <Notch> There are 0 of a max of 20 players online", &config));
+    assert!(!answers_probe("[12:00:00 INFO]: <Notch> There are 0 of a max of 20 players online", &config));
+    assert!(!answers_probe("There are 0 of a max of 20 players online: ", &config));
+  }
+
+  #[test]
+  fn launch_probe_answers() {
+    let config = Config {
+      launch: Some(LaunchConfig { program: "bedrock_server".into(), args: Vec::new() }),
+      ..Config::default()
+    };
+    assert!(answers_probe("NO LOG FILE! - There are 0/10 players online:", &config));
+    assert!(!answers_probe("NO LOG FILE! - Running AutoCompaction...", &config));
+  }
+}